@@ -1,6 +1,6 @@
 use std::borrow::BorrowMut;
 
-use super::types::Item;
+use super::types::{Item, Source};
 
 use crate::db::Database;
 use crate::util::get_conn;
@@ -32,6 +32,7 @@ pub async fn get_messages(
             Item {
                 ts: item.time,
                 content: to_raw_value(&content).unwrap(),
+                source: Source::Posted,
             }
         })
         .collect();
@@ -1,13 +1,14 @@
-use crate::STREAMS_DIR;
+use crate::CONFIG;
 
-use super::types::Item;
+use super::seek_index::SeekIndex;
+use super::types::{Item, Source};
 
 use streamwatch_shared::types::StreamInfo;
 
 use chrono::{DateTime, Utc};
 
 use tokio::fs::File;
-use tokio::io::{AsyncBufReadExt, BufReader, Lines};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader, Lines, Take};
 
 use serde_json::value::RawValue;
 
@@ -15,23 +16,37 @@ use async_compression::tokio::bufread::ZstdDecoder;
 
 use anyhow::{anyhow, Error};
 
-type LinesReader = Lines<BufReader<ZstdDecoder<BufReader<File>>>>;
+type LinesReader = Lines<BufReader<ZstdDecoder<BufReader<Take<File>>>>>;
 
 pub struct FileReader {
     orphan: Option<(DateTime<Utc>, Box<RawValue>)>,
     prev_datetime: DateTime<Utc>,
     stream: StreamInfo,
+    seek_index: Option<SeekIndex>,
     lines: LinesReader,
 }
 
 impl FileReader {
-    async fn create_lines(stream: &StreamInfo) -> Result<LinesReader, Error> {
-        let f = File::open(stream.file_name.chat_file_path(STREAMS_DIR)).await?;
-        let reader = BufReader::new(ZstdDecoder::new(BufReader::new(f)));
-        Ok(reader.lines())
+    /// Read the chat from `start`, or from the beginning without a seek index.
+    async fn create_lines(
+        stream: &StreamInfo,
+        seek_index: Option<&SeekIndex>,
+        start: DateTime<Utc>,
+    ) -> Result<LinesReader, Error> {
+        let f = match seek_index {
+            Some(index) => index.open_at(start).await?,
+            None => {
+                let streams_dir = CONFIG.get().unwrap().library_dir(&stream.library)?;
+                let f = File::open(stream.file_name.chat_file_path(streams_dir)).await?;
+                f.take(u64::MAX)
+            }
+        };
+        let mut decoder = ZstdDecoder::new(BufReader::new(f));
+        decoder.multiple_members(true);
+        Ok(BufReader::new(decoder).lines())
     }
 
-    fn parse_line(line: &str) -> Result<(DateTime<Utc>, &str), Error> {
+    pub(super) fn parse_line(line: &str) -> Result<(DateTime<Utc>, &str), Error> {
         let (date, json) = line
             .split_once(' ')
             .ok_or_else(|| anyhow!("failed to parse line"))?;
@@ -40,12 +55,17 @@ impl FileReader {
     }
 
     pub async fn new(stream: StreamInfo) -> Result<Self, Error> {
-        let lines = Self::create_lines(&stream).await?;
+        let seek_index = SeekIndex::load(&stream).await.unwrap_or_else(|e| {
+            eprintln!("[{}] failed to load chat seek index: {:?}", stream.id, e);
+            None
+        });
+        let lines = Self::create_lines(&stream, seek_index.as_ref(), stream.timestamp).await?;
 
         Ok(Self {
             orphan: None,
             prev_datetime: stream.timestamp,
             stream,
+            seek_index,
             lines,
         })
     }
@@ -61,18 +81,27 @@ impl FileReader {
                 res.push(Item {
                     ts: $datetime,
                     content: $json,
+                    source: Source::Recorded,
                 });
                 self.prev_datetime = $datetime;
             };
         }
 
-        if start < self.prev_datetime {
-            // we are going back to the past, so we have to reopen the file to seek to the file
-            // start.
-            eprintln!("!!! seeking back from {} to {}", self.prev_datetime, start);
-            self.lines = Self::create_lines(&self.stream).await?;
+        // with a seek index, jumping ahead is faster than reading up to the start.
+        let skips_frames = self.seek_index.as_ref().is_some_and(|index| {
+            index.offset_before(start) > index.offset_before(self.prev_datetime)
+        });
+        if start < self.prev_datetime || skips_frames {
+            if self.seek_index.is_none() {
+                // we are going back to the past, so we have to reopen the file to seek to the
+                // file start.
+                eprintln!("!!! seeking back from {} to {}", self.prev_datetime, start);
+            }
+            self.lines = Self::create_lines(&self.stream, self.seek_index.as_ref(), start).await?;
             self.orphan = None;
         }
+        // everything before the start is skipped, going back to it means reopening.
+        self.prev_datetime = self.prev_datetime.max(start);
 
         if let Some((datetime, json)) = self.orphan.take() {
             if start <= datetime && datetime <= end {
@@ -1,6 +1,8 @@
+use super::db;
 use super::file_reader::FileReader;
 use super::types::Item;
-use crate::{check, conn, db::Database, util::AnyhowError, DB, STREAMS_DIR};
+use crate::util::merge;
+use crate::{check, conn, db::Database, util::AnyhowError, CONFIG, DB};
 
 use std::collections::hash_map::{Entry, HashMap};
 
@@ -14,6 +16,8 @@ use once_cell::sync::Lazy;
 
 use uuid::Uuid;
 
+use anyhow::{anyhow, Result};
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct Request {
     session_token: Option<Uuid>,
@@ -65,6 +69,28 @@ pub async fn cache_pruner() {
     }
 }
 
+/// The recorded chat from `reader`, if the stream has a chat file, together with the messages
+/// viewers posted.
+pub(super) async fn get_items(
+    reader: Option<&mut FileReader>,
+    stream_id: i64,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Result<Vec<Item>> {
+    let mut file_items = match reader {
+        Some(reader) => reader.get_between(start, end).await?,
+        None => vec![],
+    };
+    // Recorded chat is sometimes a little out of order, it still has to be shown.
+    if !file_items.is_sorted_by_key(|item| item.ts) {
+        file_items.sort_by_key(|item| item.ts);
+    }
+    let db_items = db::get_messages(stream_id, start, end).await?;
+    merge(file_items, db_items, |item| item.ts).ok_or_else(|| anyhow!("chat is not sorted"))
+}
+
+/// The chat between two points of a stream. Clients that poll this keep their `FileReader` in a
+/// cache with their session token, the chat replay WebSocket keeps it for the whole connection.
 pub async fn handle_chat_request(
     stream_id: i64,
     request: Request,
@@ -93,7 +119,8 @@ pub async fn handle_chat_request(
                     Some(s) => s,
                 };
 
-                let file_reader = if check!(stream.info.file_name.has_chat(STREAMS_DIR).await) {
+                let streams_dir = check!(CONFIG.get().unwrap().library_dir(&stream.info.library));
+                let file_reader = if check!(stream.info.file_name.has_chat(streams_dir).await) {
                     Some(check!(FileReader::new(stream.info).await))
                 } else {
                     None
@@ -109,13 +136,7 @@ pub async fn handle_chat_request(
             }
         };
 
-        let file_messages = match file_reader {
-            Some(reader) => check!(reader.get_between(request.start, request.end).await),
-            None => vec![],
-        };
-        //let db_messages = check!(db::get_messages(stream_id, request.start, request.end).await);
-        //merge(file_messages, db_messages, |x| x.ts).unwrap()
-        file_messages
+        check!(get_items(file_reader.as_mut(), stream_id, request.start, request.end).await)
     };
 
     Ok(warp::reply::json(&Response {
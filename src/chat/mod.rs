@@ -1,7 +1,11 @@
 mod db;
 mod file_reader;
 mod handler;
+mod replay;
+mod seek_index;
 mod types;
 
 pub use file_reader::FileReader;
 pub use handler::{cache_pruner, handle_chat_request};
+pub use replay::chat_replay_ws;
+pub use seek_index::{build_seek_index, needs_seek_index};
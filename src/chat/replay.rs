@@ -0,0 +1,319 @@
+//! Chat replay over a WebSocket. The client sends its playback state whenever it changes (play,
+//! pause, seek or a different rate) and the chat is pushed when playback reaches it. Chat is read
+//! ahead a little, messages posted in that part of the stream show up after the next seek.
+use super::file_reader::FileReader;
+use super::handler::get_items;
+use super::types::Item;
+use crate::{check, conn, db::Database, util::AnyhowError, CONFIG, DB};
+
+use std::collections::VecDeque;
+use std::time::Instant;
+
+use chrono::{DateTime, Duration, Utc};
+
+use futures::{SinkExt, StreamExt};
+
+use tokio::time::timeout;
+
+use warp::{
+    ws::{Message, WebSocket, Ws},
+    Reply,
+};
+
+use serde::{Deserialize, Serialize};
+
+use anyhow::{anyhow, Result};
+
+/// After a seek, this much chat from before the position is sent so the chat isn't empty.
+const BACKLOG_SECS: i64 = 30;
+/// Chat is read from the file this much at a time.
+const READ_AHEAD_SECS: i64 = 10;
+/// A position that is further off from where playback should be counts as a seek.
+const SEEK_TOLERANCE_MILLIS: i64 = 2000;
+/// Players don't go faster than this, and it keeps where playback should be in range.
+const MAX_RATE: f64 = 16.0;
+
+const fn default_rate() -> f64 {
+    1.0
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct PlaybackState {
+    /// Seconds since the start of the stream.
+    position: f64,
+    #[serde(default = "default_rate")]
+    rate: f64,
+    playing: bool,
+}
+
+impl PlaybackState {
+    /// Whether the position is in a stream of `length` and the rate is one a player would use.
+    fn is_valid(&self, length: Duration) -> bool {
+        self.position.is_finite()
+            && self.position >= 0.0
+            && self.position * 1000.0 <= length.num_milliseconds() as f64
+            && self.rate.is_finite()
+            && self.rate > 0.0
+            && self.rate <= MAX_RATE
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct Push {
+    /// The client should clear its chat first, playback went somewhere else.
+    reset: bool,
+    items: Vec<Item>,
+}
+
+struct Playback {
+    at: DateTime<Utc>,
+    since: Instant,
+    rate: f64,
+    playing: bool,
+}
+
+impl Playback {
+    /// Where playback should be by now.
+    fn now(&self) -> Result<DateTime<Utc>> {
+        if !self.playing {
+            return Ok(self.at);
+        }
+        let elapsed = self.since.elapsed().as_secs_f64() * self.rate;
+        self.at
+            .checked_add_signed(Duration::microseconds((elapsed * 1_000_000.0) as i64))
+            .ok_or_else(|| anyhow!("playback is out of range"))
+    }
+}
+
+struct Replay {
+    stream_id: i64,
+    /// `None` if the stream has no chat file, there can still be messages posted by viewers.
+    reader: Option<FileReader>,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    /// `None` until the client sent its playback state.
+    playback: Option<Playback>,
+    /// Chat that was read but isn't due yet.
+    buffer: VecDeque<Item>,
+    /// Everything before this was read from the file.
+    read_until: DateTime<Utc>,
+}
+
+impl Replay {
+    /// Follow the playback of the client, after a seek this is the chat to start with.
+    async fn set_playback(&mut self, state: PlaybackState) -> Result<Option<Push>> {
+        let at = self
+            .start
+            .checked_add_signed(Duration::milliseconds((state.position * 1000.0) as i64))
+            .ok_or_else(|| anyhow!("playback position {}s is out of range", state.position))?;
+        let seeked = match &self.playback {
+            None => true,
+            Some(playback) => {
+                (playback.now()? - at).num_milliseconds().abs() > SEEK_TOLERANCE_MILLIS
+            }
+        };
+
+        self.playback = Some(Playback {
+            at,
+            since: Instant::now(),
+            rate: state.rate,
+            playing: state.playing,
+        });
+
+        if !seeked {
+            // just drifted a bit, what was sent already is not sent again.
+            return Ok(None);
+        }
+
+        let items = get_items(
+            self.reader.as_mut(),
+            self.stream_id,
+            at - Duration::seconds(BACKLOG_SECS),
+            at,
+        )
+        .await?;
+        self.buffer.clear();
+        self.read_until = at + Duration::milliseconds(1);
+
+        Ok(Some(Push { reset: true, items }))
+    }
+
+    /// The chat that playback reached, and the next chat is read.
+    async fn take_due(&mut self) -> Result<Vec<Item>> {
+        let now = match &self.playback {
+            Some(playback) if playback.playing => playback.now()?,
+            _ => return Ok(vec![]),
+        };
+
+        let mut due = vec![];
+        loop {
+            while self.buffer.front().is_some_and(|item| item.ts <= now) {
+                due.extend(self.buffer.pop_front());
+            }
+            if !self.buffer.is_empty() || self.read_until > self.end {
+                break;
+            }
+
+            let until = self.read_until + Duration::seconds(READ_AHEAD_SECS);
+            let items = get_items(
+                self.reader.as_mut(),
+                self.stream_id,
+                self.read_until,
+                until - Duration::milliseconds(1),
+            )
+            .await?;
+            self.buffer.extend(items);
+            self.read_until = until;
+        }
+
+        Ok(due)
+    }
+
+    /// How long until the next chat is due, `None` if that depends on the client.
+    fn until_next(&self) -> Result<Option<std::time::Duration>> {
+        let (playback, next) = match (
+            self.playback.as_ref().filter(|p| p.playing),
+            self.buffer.front(),
+        ) {
+            (Some(playback), Some(next)) => (playback, next),
+            _ => return Ok(None),
+        };
+        let wait = (next.ts - playback.now()?).to_std().unwrap_or_default();
+        Ok(Some(wait.div_f64(playback.rate)))
+    }
+}
+
+async fn send(ws: &mut WebSocket, push: &Push) -> Result<()> {
+    ws.send(Message::text(serde_json::to_string(push)?)).await?;
+    Ok(())
+}
+
+async fn replay(mut ws: WebSocket, mut replay: Replay) -> Result<()> {
+    loop {
+        let items = replay.take_due().await?;
+        if !items.is_empty() {
+            let push = Push {
+                reset: false,
+                items,
+            };
+            send(&mut ws, &push).await?;
+        }
+
+        let msg = match replay.until_next()? {
+            Some(wait) => match timeout(wait, ws.next()).await {
+                Ok(msg) => msg,
+                Err(_) => continue,
+            },
+            None => ws.next().await,
+        };
+        let msg = match msg {
+            None => return Ok(()),
+            Some(msg) => msg?,
+        };
+        if msg.is_close() {
+            return Ok(());
+        }
+        // pings are answered by warp
+        let text = match msg.to_str() {
+            Ok(text) => text,
+            Err(()) => continue,
+        };
+
+        let state: PlaybackState = match serde_json::from_str(text) {
+            Ok(state) => state,
+            Err(e) => {
+                eprintln!("invalid playback state in chat replay: {}", e);
+                continue;
+            }
+        };
+        if !state.is_valid(replay.end - replay.start) {
+            eprintln!("invalid playback state in chat replay: {:?}", state);
+            continue;
+        }
+
+        if let Some(push) = replay.set_playback(state).await? {
+            send(&mut ws, &push).await?;
+        }
+    }
+}
+
+pub async fn chat_replay_ws(
+    stream_id: i64,
+    ws: Ws,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let stream = match check!(Database::get_stream_by_id(conn!(), stream_id).await) {
+        None => return Err(warp::reject::not_found()),
+        Some(s) => s,
+    };
+    let start = stream.info.timestamp;
+    let end = start + check!(Duration::from_std(stream.info.duration));
+    let streams_dir = check!(CONFIG.get().unwrap().library_dir(&stream.info.library));
+    let reader = if check!(stream.info.file_name.has_chat(streams_dir).await) {
+        Some(check!(FileReader::new(stream.info).await))
+    } else {
+        None
+    };
+
+    let res = ws.on_upgrade(move |ws| async move {
+        let state = Replay {
+            stream_id,
+            reader,
+            start,
+            end,
+            playback: None,
+            buffer: VecDeque::new(),
+            read_until: start,
+        };
+        if let Err(e) = replay(ws, state).await {
+            eprintln!("[{}] chat replay stopped: {:?}", stream_id, e);
+        }
+    });
+    Ok(res.into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replay(length: Duration) -> Replay {
+        let start = Utc::now();
+        Replay {
+            stream_id: 1,
+            reader: None,
+            start,
+            end: start + length,
+            playback: None,
+            buffer: VecDeque::new(),
+            read_until: start,
+        }
+    }
+
+    fn state(position: f64, rate: f64) -> PlaybackState {
+        PlaybackState {
+            position,
+            rate,
+            playing: true,
+        }
+    }
+
+    #[test]
+    fn playback_state_is_in_the_stream() {
+        let length = Duration::hours(1);
+        assert!(state(0.0, 1.0).is_valid(length));
+        assert!(state(3600.0, MAX_RATE).is_valid(length));
+        assert!(!state(3600.5, 1.0).is_valid(length));
+        assert!(!state(1e300, 1.0).is_valid(length));
+        assert!(!state(-1.0, 1.0).is_valid(length));
+        assert!(!state(f64::NAN, 1.0).is_valid(length));
+        assert!(!state(10.0, 0.0).is_valid(length));
+        assert!(!state(10.0, 1e300).is_valid(length));
+        assert!(!state(10.0, f64::INFINITY).is_valid(length));
+    }
+
+    #[tokio::test]
+    async fn out_of_range_position_is_an_error() {
+        let mut replay = replay(Duration::hours(1));
+        assert!(replay.set_playback(state(1e300, 1.0)).await.is_err());
+        assert!(replay.playback.is_none());
+    }
+}
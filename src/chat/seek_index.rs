@@ -0,0 +1,282 @@
+//! Chat files are one zstd stream, so reading from a point in the middle means decompressing
+//! everything before it. The seek index is a copy of the chat file with the lines compressed in
+//! independent frames, followed by a skippable frame with the first timestamp and the offset of
+//! every frame. It is still a normal zstd file, `zstd -d` skips the index.
+use super::file_reader::FileReader;
+use crate::CONFIG;
+
+use streamwatch_shared::types::StreamInfo;
+
+use std::io::{ErrorKind, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, TimeZone, Utc};
+
+use tokio::fs::{create_dir_all, metadata, rename, File};
+use tokio::io::{
+    AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader, BufWriter, Take,
+};
+
+use async_compression::tokio::bufread::ZstdDecoder;
+use async_compression::tokio::write::ZstdEncoder;
+
+use anyhow::{anyhow, bail, Result};
+
+/// Uncompressed size of a frame, a seek decompresses at most this much before the start.
+const FRAME_SIZE: usize = 256 * 1024;
+/// Decoders skip frames starting with 0x184D2A50 to 0x184D2A5F.
+const SKIPPABLE_FRAME_MAGIC: u32 = 0x184D2A5E;
+const SKIPPABLE_FRAME_HEADER_SIZE: u64 = 8;
+const INDEX_TAG: &[u8; 4] = b"SWSI";
+/// A timestamp in milliseconds and an offset.
+const INDEX_ENTRY_SIZE: u64 = 16;
+/// The number of entries and the tag, at the very end of the file.
+const INDEX_FOOTER_SIZE: u64 = 8;
+
+pub struct SeekIndex {
+    path: PathBuf,
+    /// The timestamp of the first line of every frame, with the offset of the frame.
+    points: Vec<(DateTime<Utc>, u64)>,
+    /// Where the frames end and the index starts.
+    end: u64,
+}
+
+impl SeekIndex {
+    /// The seek index of a stream's chat, `None` if it wasn't built since the chat file changed.
+    pub async fn load(stream: &StreamInfo) -> Result<Option<Self>> {
+        let config = CONFIG.get().unwrap();
+        let streams_dir = config.library_dir(&stream.library)?;
+        let path = StreamInfo::chat_seek_index_path(&config.chat_index_dir, stream.id);
+        if !is_up_to_date(&stream.file_name.chat_file_path(streams_dir), &path).await? {
+            return Ok(None);
+        }
+        Ok(Some(Self::read(path).await?))
+    }
+
+    async fn read(path: PathBuf) -> Result<Self> {
+        let mut f = File::open(&path).await?;
+        let len = f.metadata().await?.len();
+        if len < SKIPPABLE_FRAME_HEADER_SIZE + INDEX_FOOTER_SIZE {
+            bail!("seek index is too short");
+        }
+
+        f.seek(SeekFrom::End(-(INDEX_FOOTER_SIZE as i64))).await?;
+        let count = f.read_u32_le().await? as u64;
+        let mut tag = [0; 4];
+        f.read_exact(&mut tag).await?;
+        if &tag != INDEX_TAG {
+            bail!("seek index doesn't end with an index");
+        }
+
+        let end = len
+            .checked_sub(SKIPPABLE_FRAME_HEADER_SIZE + count * INDEX_ENTRY_SIZE + INDEX_FOOTER_SIZE)
+            .ok_or_else(|| anyhow!("seek index is too short"))?;
+        f.seek(SeekFrom::Start(end + SKIPPABLE_FRAME_HEADER_SIZE))
+            .await?;
+
+        let mut f = BufReader::new(f);
+        let mut points = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let ts = f.read_i64_le().await?;
+            let ts = Utc
+                .timestamp_millis_opt(ts)
+                .single()
+                .ok_or_else(|| anyhow!("timestamp out of range in seek index"))?;
+            let offset = f.read_u64_le().await?;
+            points.push((ts, offset));
+        }
+
+        Ok(Self { path, points, end })
+    }
+
+    /// Offset of the last frame that starts before `ts`. Lines at `ts` can be at the end of the
+    /// frame before the one that starts at `ts`.
+    pub fn offset_before(&self, ts: DateTime<Utc>) -> u64 {
+        let i = self.points.partition_point(|(t, _)| *t < ts);
+        i.checked_sub(1).map_or(0, |i| self.points[i].1)
+    }
+
+    /// The frames from the last one that starts before `start`.
+    pub async fn open_at(&self, start: DateTime<Utc>) -> Result<Take<File>> {
+        let offset = self.offset_before(start);
+        let mut f = File::open(&self.path).await?;
+        f.seek(SeekFrom::Start(offset)).await?;
+        Ok(f.take(self.end - offset))
+    }
+}
+
+/// Whether `index` was written after the last change to `chat`.
+async fn is_up_to_date(chat: &Path, index: &Path) -> Result<bool> {
+    let index = match metadata(index).await {
+        Ok(m) => m,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(index.modified()? >= metadata(chat).await?.modified()?)
+}
+
+pub async fn needs_seek_index(stream: &StreamInfo) -> Result<bool> {
+    let config = CONFIG.get().unwrap();
+    let up_to_date = is_up_to_date(
+        &stream
+            .file_name
+            .chat_file_path(config.library_dir(&stream.library)?),
+        &StreamInfo::chat_seek_index_path(&config.chat_index_dir, stream.id),
+    )
+    .await?;
+    Ok(!up_to_date)
+}
+
+async fn compress_frame(lines: &str) -> Result<Vec<u8>> {
+    let mut encoder = ZstdEncoder::new(Vec::new());
+    encoder.write_all(lines.as_bytes()).await?;
+    encoder.shutdown().await?;
+    Ok(encoder.into_inner())
+}
+
+/// Write the seek index of a stream's chat to the chat index directory.
+pub async fn build_seek_index(stream: &StreamInfo) -> Result<()> {
+    let config = CONFIG.get().unwrap();
+    let streams_dir = config.library_dir(&stream.library)?;
+    create_dir_all(&config.chat_index_dir).await?;
+    write_seek_index(
+        &stream.file_name.chat_file_path(streams_dir),
+        &StreamInfo::chat_seek_index_path(&config.chat_index_dir, stream.id),
+        stream.timestamp,
+    )
+    .await
+}
+
+/// Write the seek index of the chat file `chat` to `path`, `start` is the timestamp of an empty
+/// chat.
+async fn write_seek_index(chat: &Path, path: &Path, start: DateTime<Utc>) -> Result<()> {
+    let part = path.with_extension("zst.part");
+
+    let f = File::open(chat).await?;
+    let mut lines = BufReader::new(ZstdDecoder::new(BufReader::new(f))).lines();
+    let mut out = BufWriter::new(File::create(&part).await?);
+
+    let mut points = vec![];
+    let mut offset = 0;
+    let mut frame = String::new();
+    let mut frame_ts = start;
+    loop {
+        let line = lines.next_line().await?;
+        if let Some(line) = &line {
+            if frame.is_empty() {
+                frame_ts = FileReader::parse_line(line)?.0;
+            }
+            frame.push_str(line);
+            frame.push('\n');
+        }
+
+        if frame.len() >= FRAME_SIZE || (line.is_none() && !frame.is_empty()) {
+            let compressed = compress_frame(&frame).await?;
+            out.write_all(&compressed).await?;
+            points.push((frame_ts, offset));
+            offset += compressed.len() as u64;
+            frame.clear();
+        }
+
+        if line.is_none() {
+            break;
+        }
+    }
+
+    let count = points.len() as u64;
+    out.write_u32_le(SKIPPABLE_FRAME_MAGIC).await?;
+    out.write_u32_le((count * INDEX_ENTRY_SIZE + INDEX_FOOTER_SIZE).try_into()?)
+        .await?;
+    for (ts, offset) in points {
+        out.write_i64_le(ts.timestamp_millis()).await?;
+        out.write_u64_le(offset).await?;
+    }
+    out.write_u32_le(count.try_into()?).await?;
+    out.write_all(INDEX_TAG).await?;
+    out.flush().await?;
+
+    rename(part, path).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::Duration;
+
+    #[tokio::test]
+    async fn seek_index_round_trip() -> Result<()> {
+        let dir =
+            std::env::temp_dir().join(format!("streamwatch-seek-index-{}", std::process::id()));
+        create_dir_all(&dir).await?;
+        let chat = dir.join("chat.txt.zst");
+        let path = dir.join("1.txt.zst");
+
+        // Half a second apart, so lines at the same second can end up in different frames.
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        let lines: Vec<(DateTime<Utc>, String)> = (0..10_000)
+            .map(|i| {
+                let ts = start + Duration::milliseconds(500 * i);
+                let line = format!(
+                    r#"{} {{"message":"{}","i":{}}}"#,
+                    ts.to_rfc3339(),
+                    "a".repeat(i as usize % 100),
+                    i
+                );
+                (ts, line)
+            })
+            .collect();
+        let mut text = lines
+            .iter()
+            .map(|(_, line)| line.as_str())
+            .collect::<Vec<_>>()
+            .join("\n");
+        text.push('\n');
+        let mut f = ZstdEncoder::new(File::create(&chat).await?);
+        f.write_all(text.as_bytes()).await?;
+        f.shutdown().await?;
+
+        write_seek_index(&chat, &path, start).await?;
+        let index = SeekIndex::read(path.clone()).await?;
+        assert!(index.points.len() > 1);
+        assert_eq!(index.points[0], (start, 0));
+        for w in index.points.windows(2) {
+            assert_eq!(index.offset_before(w[1].0), w[0].1);
+            assert_eq!(
+                index.offset_before(w[1].0 + Duration::milliseconds(1)),
+                w[1].1
+            );
+        }
+        assert_eq!(index.offset_before(start - Duration::days(1)), 0);
+
+        let end = lines.last().unwrap().0;
+        for target in [
+            start,
+            start + Duration::seconds(1234),
+            start + Duration::seconds(4321),
+            end,
+            end + Duration::days(1),
+        ] {
+            let mut decoder = ZstdDecoder::new(BufReader::new(index.open_at(target).await?));
+            decoder.multiple_members(true);
+            let mut read = vec![];
+            let mut reader = BufReader::new(decoder).lines();
+            while let Some(line) = reader.next_line().await? {
+                read.push(line);
+            }
+
+            // A suffix of the chat that starts before the target, with every line from it on.
+            let skipped = lines.len() - read.len();
+            assert!(lines[..skipped].iter().all(|(ts, _)| *ts < target));
+            assert!(lines[skipped..]
+                .iter()
+                .map(|(_, line)| line)
+                .eq(read.iter()));
+            assert_eq!(skipped > 0, index.offset_before(target) > 0);
+        }
+
+        tokio::fs::remove_dir_all(dir).await?;
+        Ok(())
+    }
+}
@@ -2,9 +2,20 @@ use chrono::{serde::ts_milliseconds, DateTime, Utc};
 use serde::Serialize;
 use serde_json::value::RawValue;
 
+/// Where a chat item comes from.
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    /// The chat file recorded with the stream.
+    Recorded,
+    /// Posted by a viewer on the VOD, from the `messages` table.
+    Posted,
+}
+
 #[derive(Clone, Debug, Serialize)]
 pub struct Item {
     #[serde(with = "ts_milliseconds")]
     pub ts: DateTime<Utc>,
     pub content: Box<RawValue>, // lazy response so we don't have to parse the json blob
+    pub source: Source,
 }
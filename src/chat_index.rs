@@ -0,0 +1,53 @@
+use crate::chat::FileReader;
+
+use streamwatch_shared::types::StreamInfo;
+
+use chrono::{DateTime, Duration, Utc};
+
+use serde_json::Value;
+
+use anyhow::Result;
+
+/// A chat message, as it is put in the search index.
+pub struct ChatLine {
+    pub ts: DateTime<Utc>,
+    pub author: String,
+    pub text: String,
+}
+
+pub async fn get_chat_lines(stream: StreamInfo) -> Result<Vec<ChatLine>> {
+    if !stream.has_chat {
+        return Ok(vec![]);
+    }
+
+    let start = stream.timestamp;
+    let end = start + Duration::from_std(stream.duration)?;
+    let items = FileReader::new(stream)
+        .await?
+        .get_between(start, end)
+        .await?;
+
+    let mut lines = vec![];
+    for item in items {
+        let content: Value = serde_json::from_str(item.content.get())?;
+        if content["type"] != "chat" {
+            continue;
+        }
+        let text = match content["message"].as_str() {
+            None => continue,
+            Some(text) => text.to_owned(),
+        };
+        let author = content["tags"]["display-name"]
+            .as_str()
+            .unwrap_or("")
+            .to_owned();
+
+        lines.push(ChatLine {
+            ts: item.ts,
+            author,
+            text,
+        });
+    }
+
+    Ok(lines)
+}
@@ -0,0 +1,339 @@
+use crate::chat::FileReader;
+use crate::create_preview::escape_filter_value;
+use crate::CONFIG;
+
+use streamwatch_shared::functions::run_command;
+use streamwatch_shared::types::StreamInfo;
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use tokio::fs::{create_dir_all, rename, try_exists};
+use tokio::process::Command;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use once_cell::sync::Lazy;
+
+use anyhow::Result;
+
+/// Width of the chat panel, in characters.
+const PANEL_COLUMNS: usize = 30;
+/// Height of the video divided by the font size.
+const FONT_SIZE_DIVISOR: u32 = 36;
+/// Below this the chat isn't readable, small videos get a wider panel instead.
+const MIN_FONT_SIZE: u32 = 12;
+/// Advance of a character of a monospace font, relative to the font size.
+const CHAR_WIDTH: f64 = 0.6;
+const LINE_HEIGHT: f64 = 1.3;
+/// Messages from this long before the clip are included, so the panel isn't empty at the start.
+const HISTORY: Duration = Duration::from_secs(60);
+
+const MAX_NAME_LENGTH: usize = 25;
+const DEFAULT_NAME_COLOR: &str = "0xb9a3e3";
+
+#[derive(Clone, Debug, Deserialize)]
+struct Emote {
+    id: i64,
+    url: String,
+    width: u32,
+    height: u32,
+}
+
+/// The channel emotes, by name.
+static EMOTES: Lazy<HashMap<String, Emote>> =
+    Lazy::new(|| serde_json::from_str(include_str!("emotes.json")).unwrap());
+
+/// Download an emote into the emotes dir, if it isn't there already.
+async fn fetch_emote(emote: &Emote) -> Result<PathBuf> {
+    let dir = Path::new(&CONFIG.get().unwrap().emotes_dir);
+    let path = dir.join(format!("{}.png", emote.id));
+    if try_exists(&path).await? {
+        return Ok(path);
+    }
+
+    create_dir_all(dir).await?;
+    let part = path.with_extension("png.part");
+    run_command(
+        Command::new("ffmpeg")
+            .args(["-hide_banner", "-loglevel", "error", "-i", &emote.url])
+            .args(["-frames:v", "1", "-f", "image2", "-c:v", "png", "-y"])
+            .arg(part.as_os_str()),
+    )
+    .await?;
+    rename(&part, &path).await?;
+
+    Ok(path)
+}
+
+enum Segment {
+    Text(String),
+    Emote(&'static Emote),
+}
+
+/// A chat message that is shown in the clip.
+struct Message {
+    /// Seconds since the start of the clip, negative if it was sent before.
+    at: f64,
+    name: String,
+    color: String,
+    /// The name takes up the start of the first line.
+    lines: Vec<Vec<(usize, Segment)>>,
+}
+
+/// Split a message into lines of at most [`PANEL_COLUMNS`] characters, starting at `column`. Every
+/// segment is returned with the column it starts at.
+fn wrap(
+    text: &str,
+    mut column: usize,
+    emote_columns: impl Fn(&Emote) -> usize,
+) -> Vec<Vec<(usize, Segment)>> {
+    // Words that don't fit on a line on their own are broken up.
+    let words = text
+        .split_whitespace()
+        .flat_map(|word| match EMOTES.get(word) {
+            Some(emote) => vec![(Segment::Emote(emote), emote_columns(emote))],
+            None => word
+                .chars()
+                .collect::<Vec<_>>()
+                .chunks(PANEL_COLUMNS)
+                .map(|chunk| (Segment::Text(chunk.iter().collect()), chunk.len()))
+                .collect(),
+        });
+
+    let mut lines = vec![vec![]];
+    for (segment, width) in words {
+        let mut start = if column == 0 { 0 } else { column + 1 };
+        if start + width > PANEL_COLUMNS && column > 0 {
+            lines.push(vec![]);
+            start = 0;
+        }
+        lines.last_mut().unwrap().push((start, segment));
+        column = start + width;
+    }
+
+    lines
+}
+
+/// Chat rendered into a panel on the right of the video.
+pub struct ChatOverlay {
+    /// Emote images, to be added as inputs after the video.
+    pub inputs: Vec<PathBuf>,
+    /// Filters adding the panel, from `[base]` to `[v]`. The emote inputs are referred to by
+    /// their index, starting at 1.
+    pub filter: String,
+}
+
+impl ChatOverlay {
+    /// Lay out the chat messages of the given part of the stream, for a video of `height`
+    /// pixels.
+    pub async fn new(
+        stream: StreamInfo,
+        start: Duration,
+        duration: Duration,
+        height: u32,
+    ) -> Result<Self> {
+        let clip_start = stream.timestamp + chrono::Duration::from_std(start)?;
+        let clip_end = clip_start + chrono::Duration::from_std(duration)?;
+        let items = FileReader::new(stream)
+            .await?
+            .get_between(clip_start - chrono::Duration::from_std(HISTORY)?, clip_end)
+            .await?;
+
+        let font_size = (height / FONT_SIZE_DIVISOR).max(MIN_FONT_SIZE);
+        let char_width = CHAR_WIDTH * font_size as f64;
+        let line_height = (LINE_HEIGHT * font_size as f64).round() as u32;
+        let padding = font_size / 2;
+        let panel_width = ((PANEL_COLUMNS as f64 * char_width) as u32 + 2 * padding + 1) & !1;
+        let rows = (height.saturating_sub(2 * padding) / line_height) as usize;
+
+        let emote_height = line_height;
+        let emote_columns = |emote: &Emote| {
+            let width = emote.width as f64 * emote_height as f64 / emote.height as f64;
+            (width / char_width).ceil() as usize
+        };
+
+        let mut messages = vec![];
+        for item in items {
+            let content: Value = serde_json::from_str(item.content.get())?;
+            if content["type"] != "chat" {
+                continue;
+            }
+            let text = match content["message"].as_str() {
+                None => continue,
+                Some(text) => text,
+            };
+            let name = content["tags"]["display-name"]
+                .as_str()
+                .unwrap_or("")
+                .replace(char::is_control, "")
+                .chars()
+                .take(MAX_NAME_LENGTH)
+                .collect::<String>();
+            let color = content["tags"]["color"]
+                .as_str()
+                .and_then(|c| c.strip_prefix('#'))
+                .filter(|c| c.len() == 6 && c.chars().all(|c| c.is_ascii_hexdigit()))
+                .map(|c| format!("0x{}", c))
+                .unwrap_or_else(|| String::from(DEFAULT_NAME_COLOR));
+
+            let at = (item.ts - clip_start).num_milliseconds() as f64 / 1000.0;
+            let lines = wrap(text, name.chars().count() + 1, emote_columns);
+            messages.push(Message {
+                at,
+                name,
+                color,
+                lines,
+            });
+        }
+
+        // Line `k` of message `i` moves up when later messages come in, and disappears when it
+        // moves out of the top of the panel.
+        let bottom = height - padding;
+        let mut drawtexts = vec![];
+        let mut emotes = vec![];
+        for (i, message) in messages.iter().enumerate() {
+            let n = message.lines.len();
+            for (k, line) in message.lines.iter().enumerate() {
+                let mut row = n - 1 - k;
+                if row >= rows {
+                    continue;
+                }
+
+                let mut shifts = vec![];
+                let mut leave = None;
+                for later in &messages[i + 1..] {
+                    row += later.lines.len();
+                    if row >= rows {
+                        leave = Some(later.at);
+                        break;
+                    }
+                    shifts.push(format!("{}*gte(t,{})", later.lines.len(), later.at));
+                }
+                if leave.is_some_and(|leave| leave <= 0.0) {
+                    continue;
+                }
+
+                let top = bottom - (n - k) as u32 * line_height;
+                let y = if shifts.is_empty() {
+                    top.to_string()
+                } else {
+                    format!("{}-{}*({})", top, line_height, shifts.join("+"))
+                };
+                let enable = match leave {
+                    None => format!("gte(t,{})", message.at),
+                    Some(leave) => format!("between(t,{},{})", message.at, leave - 0.001),
+                };
+                // Relative to the right edge of the padded video, which is `w` for drawtext and
+                // `W` for overlay.
+                let x = |column: usize| {
+                    format!(
+                        "-{}+{}",
+                        panel_width,
+                        padding + (column as f64 * char_width) as u32
+                    )
+                };
+                let drawtext = |text: &str, color: &str, column: usize| {
+                    format!(
+                        "drawtext=font=monospace:fontsize={}:fontcolor={}:text={}:expansion=none:x='w{}':y='{}':enable='{}'",
+                        font_size,
+                        color,
+                        escape_filter_value(text),
+                        x(column),
+                        y,
+                        enable,
+                    )
+                };
+
+                if k == 0 {
+                    drawtexts.push(drawtext(&format!("{}:", message.name), &message.color, 0));
+                }
+
+                // The text of the line is drawn at once, with spaces where the emotes go. Leading
+                // whitespace would be stripped by ffmpeg, so it starts at the first word.
+                let mut text: Option<(usize, String)> = None;
+                for (column, segment) in line {
+                    match segment {
+                        Segment::Text(word) => match &mut text {
+                            None => text = Some((*column, word.clone())),
+                            Some((start, text)) => {
+                                let len = *start + text.chars().count();
+                                text.extend(std::iter::repeat_n(' ', column - len));
+                                *text += word;
+                            }
+                        },
+                        Segment::Emote(emote) => emotes.push((
+                            *emote,
+                            format!("W{}", x(*column)),
+                            y.clone(),
+                            enable.clone(),
+                        )),
+                    }
+                }
+                if let Some((column, text)) = text {
+                    drawtexts.push(drawtext(&text, "white", column));
+                }
+            }
+        }
+
+        let mut filter = format!("[base]pad=iw+{}:ih:0:0:color=0x18181b", panel_width);
+        for drawtext in drawtexts {
+            filter += ",";
+            filter += &drawtext;
+        }
+
+        // Every emote image is an input, split into as many copies as it is shown.
+        let mut inputs = vec![];
+        let mut uses: HashMap<i64, (usize, usize)> = HashMap::new();
+        let mut overlays = vec![];
+        for (emote, x, y, enable) in emotes {
+            let input = match uses.get_mut(&emote.id) {
+                Some((input, count)) => {
+                    *count += 1;
+                    Some((*input, *count - 1))
+                }
+                None => match fetch_emote(emote).await {
+                    Ok(path) => {
+                        inputs.push(path);
+                        uses.insert(emote.id, (inputs.len(), 1));
+                        Some((inputs.len(), 0))
+                    }
+                    Err(e) => {
+                        eprintln!("failed to fetch emote {}: {:?}", emote.id, e);
+                        None
+                    }
+                },
+            };
+            if let Some((input, copy)) = input {
+                overlays.push((input, copy, x, y, enable));
+            }
+        }
+
+        filter += "[chat]";
+        let mut uses: Vec<_> = uses.into_values().collect();
+        uses.sort();
+        for (input, count) in uses {
+            filter += &format!(
+                ";[{}:v]scale=-1:{},format=rgba,split={}",
+                input, emote_height, count
+            );
+            for copy in 0..count {
+                filter += &format!("[e{}_{}]", input, copy);
+            }
+        }
+
+        let mut last = String::from("chat");
+        for (i, (input, copy, x, y, enable)) in overlays.into_iter().enumerate() {
+            filter += &format!(
+                ";[{}][e{}_{}]overlay=x='{}':y='{}':enable='{}':shortest=1[o{}]",
+                last, input, copy, x, y, enable, i
+            );
+            last = format!("o{}", i);
+        }
+        filter += &format!(";[{}]null[v]", last);
+
+        Ok(Self { inputs, filter })
+    }
+}
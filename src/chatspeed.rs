@@ -8,7 +8,7 @@ use crate::chat::FileReader;
 use streamwatch_shared::types::StreamInfo;
 
 pub async fn get_chatspeed_points(stream: StreamInfo) -> Result<Vec<(DateTime<Utc>, usize)>> {
-    if !stream.has_chat || vec![614].contains(&stream.id) {
+    if !stream.has_chat {
         return Ok(vec![]);
     }
 
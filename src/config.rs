@@ -0,0 +1,229 @@
+use crate::hype::HypeModel;
+
+use std::collections::HashSet;
+use std::env;
+use std::io::ErrorKind;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use tokio::fs::read_to_string;
+
+use serde::Deserialize;
+
+use anyhow::{anyhow, bail, Result};
+
+const CONFIG_PATH_ENV: &str = "STREAMWATCH_CONFIG";
+const DEFAULT_CONFIG_PATH: &str = "./config.yaml";
+
+/// A named directory containing stream recordings. The name is stored with every stream and used
+/// in the `/stream/{library}/...` URLs, so it should not be changed once streams are indexed.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Library {
+    pub name: String,
+    pub path: String,
+}
+
+/// Parse a library list from the `name=path,name=path` format used in the environment.
+fn parse_libraries(s: &str) -> Result<Vec<Library>> {
+    s.split(',')
+        .filter(|item| !item.is_empty())
+        .map(|item| {
+            let (name, path) = item
+                .split_once('=')
+                .ok_or_else(|| anyhow!("expected name=path, got {:?}", item))?;
+            Ok(Library {
+                name: name.to_owned(),
+                path: path.to_owned(),
+            })
+        })
+        .collect()
+}
+
+/// Runtime configuration, read from a YAML file and then overridden by `STREAMWATCH_*`
+/// environment variables.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub libraries: Vec<Library>,
+    pub database_url: String,
+
+    pub address: IpAddr,
+    pub port: u16,
+
+    pub preview_workers: usize,
+    /// How often a job that failed with a transient error is tried before giving up.
+    pub job_max_attempts: i64,
+    /// How long to wait before the first retry of a failed job, doubled on every retry.
+    pub job_retry_base_secs: u64,
+
+    /// Watch the libraries for changes and scan changed files automatically.
+    pub watch_libraries: bool,
+    /// How long a file has to be left alone before the watcher scans it.
+    pub watch_debounce_secs: u64,
+
+    /// How long a login session stays valid.
+    pub session_lifetime_days: i64,
+
+    pub previews_dir: String,
+    pub thumbnails_dir: String,
+    pub scrub_thumbnails_dir: String,
+
+    /// Convert streams into adaptive HLS renditions.
+    pub generate_hls: bool,
+    pub hls_dir: String,
+
+    /// Where full quality clip exports are kept.
+    pub exports_dir: String,
+    /// Where emote images are cached for rendering chat into clip exports.
+    pub emotes_dir: String,
+    /// Where the chat files are copied to with a seek index, the libraries can be read-only.
+    pub chat_index_dir: String,
+
+    /// How hype is computed from loudness and chat speed.
+    pub hype: HypeModel,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            libraries: vec![Library {
+                name: String::from("lekkerspelen"),
+                path: String::from("/streams/lekkerspelen"),
+            }],
+            database_url: String::from("sqlite:./db.db"),
+
+            address: IpAddr::from_str("::0").unwrap(),
+            port: 6070,
+
+            preview_workers: 4,
+            job_max_attempts: 5,
+            job_retry_base_secs: 60,
+
+            watch_libraries: true,
+            watch_debounce_secs: 30,
+
+            session_lifetime_days: 30,
+
+            previews_dir: String::from("./previews"),
+            thumbnails_dir: String::from("./thumbnails"),
+            scrub_thumbnails_dir: String::from("./scrub_thumbnails"),
+
+            generate_hls: false,
+            hls_dir: String::from("./hls"),
+
+            exports_dir: String::from("./exports"),
+            emotes_dir: String::from("./emotes"),
+            chat_index_dir: String::from("./chat_index"),
+
+            hype: HypeModel::default(),
+        }
+    }
+}
+
+fn env_override<T>(field: &mut T, key: &str) -> Result<()>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    match env::var(key) {
+        Ok(val) => {
+            *field = val
+                .parse()
+                .map_err(|e| anyhow!("invalid value for {}: {}", key, e))?;
+            Ok(())
+        }
+        Err(env::VarError::NotPresent) => Ok(()),
+        Err(e) => Err(anyhow!("invalid value for {}: {}", key, e)),
+    }
+}
+
+impl Config {
+    /// Load the config from the file at `$STREAMWATCH_CONFIG` (or `./config.yaml`), falling back
+    /// to the defaults if it doesn't exist, and apply environment overrides on top.
+    pub async fn load() -> Result<Self> {
+        let (path, explicit) = match env::var(CONFIG_PATH_ENV) {
+            Ok(path) => (path, true),
+            Err(_) => (DEFAULT_CONFIG_PATH.to_owned(), false),
+        };
+
+        let mut config: Config = match read_to_string(&path).await {
+            Ok(s) => serde_yaml::from_str(&s)
+                .map_err(|e| anyhow!("error parsing config file {}: {}", path, e))?,
+            Err(e) if e.kind() == ErrorKind::NotFound && !explicit => Config::default(),
+            Err(e) => return Err(anyhow!("error reading config file {}: {}", path, e)),
+        };
+
+        if let Ok(libraries) = env::var("STREAMWATCH_LIBRARIES") {
+            config.libraries = parse_libraries(&libraries)
+                .map_err(|e| anyhow!("invalid value for STREAMWATCH_LIBRARIES: {}", e))?;
+        }
+        env_override(&mut config.database_url, "STREAMWATCH_DATABASE_URL")?;
+        env_override(&mut config.address, "STREAMWATCH_ADDRESS")?;
+        env_override(&mut config.port, "STREAMWATCH_PORT")?;
+        env_override(&mut config.preview_workers, "STREAMWATCH_PREVIEW_WORKERS")?;
+        env_override(&mut config.job_max_attempts, "STREAMWATCH_JOB_MAX_ATTEMPTS")?;
+        env_override(
+            &mut config.job_retry_base_secs,
+            "STREAMWATCH_JOB_RETRY_BASE_SECS",
+        )?;
+        env_override(&mut config.watch_libraries, "STREAMWATCH_WATCH_LIBRARIES")?;
+        env_override(
+            &mut config.watch_debounce_secs,
+            "STREAMWATCH_WATCH_DEBOUNCE_SECS",
+        )?;
+        env_override(
+            &mut config.session_lifetime_days,
+            "STREAMWATCH_SESSION_LIFETIME_DAYS",
+        )?;
+        env_override(&mut config.previews_dir, "STREAMWATCH_PREVIEWS_DIR")?;
+        env_override(&mut config.thumbnails_dir, "STREAMWATCH_THUMBNAILS_DIR")?;
+        env_override(
+            &mut config.scrub_thumbnails_dir,
+            "STREAMWATCH_SCRUB_THUMBNAILS_DIR",
+        )?;
+        env_override(&mut config.generate_hls, "STREAMWATCH_GENERATE_HLS")?;
+        env_override(&mut config.hls_dir, "STREAMWATCH_HLS_DIR")?;
+        env_override(&mut config.exports_dir, "STREAMWATCH_EXPORTS_DIR")?;
+        env_override(&mut config.emotes_dir, "STREAMWATCH_EMOTES_DIR")?;
+        env_override(&mut config.chat_index_dir, "STREAMWATCH_CHAT_INDEX_DIR")?;
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.libraries.is_empty() {
+            bail!("at least one library has to be configured");
+        }
+
+        let mut names = HashSet::new();
+        for library in &self.libraries {
+            if library.name.is_empty() || library.name.contains('/') {
+                bail!("invalid library name: {:?}", library.name);
+            }
+            if !names.insert(&library.name) {
+                bail!("duplicate library name: {:?}", library.name);
+            }
+        }
+
+        self.hype.validate()?;
+
+        Ok(())
+    }
+
+    /// The first configured library. Streams indexed before multiple libraries were supported
+    /// are assigned to it, and the legacy `/stream/{file}` route serves from it.
+    pub fn default_library(&self) -> &Library {
+        &self.libraries[0]
+    }
+
+    /// Returns the directory of the library with the given name.
+    pub fn library_dir(&self, name: &str) -> Result<&str> {
+        self.libraries
+            .iter()
+            .find(|l| l.name == name)
+            .map(|l| l.path.as_str())
+            .ok_or_else(|| anyhow!("unknown library: {:?}", name))
+    }
+}
@@ -1,15 +1,17 @@
-use streamwatch_shared::functions::get_video_duration;
+use crate::chat_overlay::ChatOverlay;
+
+use streamwatch_shared::functions::{get_video_duration, run_command, CommandError};
 
-use std::io;
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 
-use tokio::fs::create_dir_all;
+use tokio::fs::{copy, create_dir_all, metadata, remove_file, rename, write};
 use tokio::process::Command;
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 
 const SECTION_DURATION_SECS: i32 = 1;
+const HLS_SEGMENT_SECS: u32 = 6;
 pub const PREVIEW_PER_SECS: f64 = 900.0;
 pub const SCRUB_PER_SECS: f64 = 30.0;
 
@@ -30,91 +32,352 @@ pub async fn get_sections_from_file(path: &Path, per_n_secs: f64) -> Result<Vec<
     Ok(get_sections(&duration, per_n_secs))
 }
 
-/// Creates webp thumbnails
+/// Hype is averaged over this many seconds before looking for peaks, so that a single loud
+/// second doesn't win over a moment that stays exciting for a while.
+const HYPE_SMOOTHING_SECS: usize = 5;
+/// How close sections taken from hype peaks may be to each other, relative to the distance
+/// between evenly spaced sections.
+const HYPE_MIN_SPACING: f64 = 0.5;
+
+/// Like [`get_sections`], but at the highest peaks of `hype`, given as `(second, hype)` pairs.
+///
+/// Peaks too close to one that was already picked are skipped, so a single exciting moment
+/// doesn't take up the whole preview. If there aren't enough peaks, the rest is filled up with
+/// evenly spaced sections. Returns `None` if there is no hype to go by at all.
+fn get_hype_sections(
+    duration: &Duration,
+    per_n_secs: f64,
+    hype: &[(i32, f64)],
+) -> Option<Vec<(i32, i32)>> {
+    let even = get_sections(duration, per_n_secs);
+    let last_start = duration.as_secs() as i32 - SECTION_DURATION_SECS;
+    let min_spacing = (HYPE_MIN_SPACING * duration.as_secs_f64() / (even.len() + 1) as f64) as i32;
+
+    let mut hype: Vec<_> = hype
+        .iter()
+        .filter(|(s, _)| (0..=last_start).contains(s))
+        .copied()
+        .collect();
+    hype.sort_by_key(|(s, _)| *s);
+    let mut peaks: Vec<(i32, f64)> = hype
+        .windows(HYPE_SMOOTHING_SECS.min(hype.len()).max(1))
+        .map(|w| {
+            let mean = w.iter().map(|(_, h)| h).sum::<f64>() / w.len() as f64;
+            (w[w.len() / 2].0, mean)
+        })
+        .filter(|(_, h)| *h > 0.0)
+        .collect();
+    if peaks.is_empty() {
+        return None;
+    }
+    peaks.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    let mut starts: Vec<i32> = vec![];
+    let candidates = peaks
+        .into_iter()
+        .map(|(s, _)| s)
+        .chain(even.iter().map(|(s, _)| *s));
+    for start in candidates {
+        if starts.len() == even.len() {
+            break;
+        }
+        if starts.iter().all(|s| (s - start).abs() >= min_spacing) {
+            starts.push(start);
+        }
+    }
+    starts.sort();
+
+    Some(
+        starts
+            .into_iter()
+            .map(|start| (start, start + SECTION_DURATION_SECS))
+            .collect(),
+    )
+}
+
+/// Sections for the preview of a stream: at its hype peaks if there is hype data, evenly spaced
+/// otherwise. `hype` is given as `(second, hype)` pairs.
+pub async fn get_preview_sections_from_file(
+    path: &Path,
+    per_n_secs: f64,
+    hype: &[(i32, f64)],
+) -> Result<Vec<(i32, i32)>> {
+    let duration = get_video_duration(path).await?;
+    Ok(get_hype_sections(&duration, per_n_secs, hype)
+        .unwrap_or_else(|| get_sections(&duration, per_n_secs)))
+}
+
+/// How many frames are extracted by a single ffmpeg process. Every frame is a separate input, so
+/// this bounds the number of open decoders.
+const THUMBNAIL_BATCH: usize = 32;
+
+/// The thumbnails made by [`create_thumbnails`].
+pub struct Thumbnails {
+    /// `{i}.webp` for every time location `i`, empty if none could be made.
+    pub outputs: Vec<PathBuf>,
+    /// The time locations for which no thumbnail could be made, with the reason.
+    pub failures: Vec<(f64, anyhow::Error)>,
+}
+
+async fn is_nonempty_file(path: &Path) -> bool {
+    metadata(path).await.is_ok_and(|m| m.len() > 0)
+}
+
+/// Run a single ffmpeg process that seeks to every time location and writes the keyframe there to
+/// the matching output.
+async fn extract_frames(path: &Path, frames: &[(f64, PathBuf)]) -> Result<(), CommandError> {
+    let mut cmd = Command::new("nice");
+    cmd.args(["-n10", "ffmpeg", "-hide_banner", "-loglevel", "error"]);
+    for (loc, _) in frames {
+        cmd.args(["-noaccurate_seek", "-ss", &loc.to_string(), "-i"]);
+        cmd.arg(path.as_os_str());
+    }
+    for (i, (_, output)) in frames.iter().enumerate() {
+        cmd.args([
+            "-map",
+            &format!("{}:v:0", i),
+            "-frames:v",
+            "1",
+            "-f",
+            "webp",
+            "-y",
+        ]);
+        cmd.arg(output.as_os_str());
+    }
+    run_command(&mut cmd).await?;
+    Ok(())
+}
+
+/// Creates webp thumbnails at the given time locations, in batches of frames that are extracted
+/// by one ffmpeg process each.
+///
+/// Frames that are missing after a batch, either because the batch failed or because there was
+/// nothing to extract, are tried again one by one so that a single bad location doesn't lose the
+/// others. Those that still fail are returned in [`Thumbnails::failures`], and get a copy of the
+/// thumbnail before them so the thumbnail of every time location keeps its number.
 pub async fn create_thumbnails(
     path: &Path,
     output_dir: &Path,
-    time_locations: &[i32],
-) -> io::Result<Vec<PathBuf>> {
+    time_locations: &[f64],
+) -> Result<Thumbnails> {
     create_dir_all(output_dir).await?;
 
-    let mut outputs = Vec::with_capacity(time_locations.len());
+    let frames: Vec<(f64, PathBuf)> = time_locations
+        .iter()
+        .enumerate()
+        .map(|(i, loc)| (*loc, output_dir.join(format!("{}.webp.part", i))))
+        .collect();
+    // Left behind by an earlier run, they would be mistaken for frames that were extracted.
+    for (_, part) in &frames {
+        let _ = remove_file(part).await;
+    }
 
-    for (i, loc) in time_locations.iter().enumerate() {
-        let output = output_dir.join(format!("{}.webp", i));
+    let mut made = vec![false; frames.len()];
+    let mut failures = vec![];
+    for (batch_index, batch) in frames.chunks(THUMBNAIL_BATCH).enumerate() {
+        match extract_frames(path, batch).await {
+            // Nothing is going to work if ffmpeg can't be started.
+            Err(e @ CommandError::Spawn { .. }) => return Err(e.into()),
+            Err(e) => eprintln!(
+                "extracting {} thumbnails from {:?} at once failed, trying them separately: {}",
+                batch.len(),
+                path,
+                e
+            ),
+            Ok(()) => {}
+        }
 
-        let mut handle = Command::new("ffmpeg")
-            .args(&[
-                "-hide_banner",
-                "-loglevel",
-                "error",
-                "-ss",
-                &loc.to_string(),
-                "-i",
-            ])
-            .arg(path.as_os_str())
-            .args(&["-frames:v", "1", "-y"])
-            .arg(output.as_os_str())
-            .spawn()?;
-        handle.wait().await?;
+        for (i, frame) in batch.iter().enumerate() {
+            let i = batch_index * THUMBNAIL_BATCH + i;
+            if is_nonempty_file(&frame.1).await {
+                made[i] = true;
+                continue;
+            }
+
+            match extract_frames(path, std::slice::from_ref(frame)).await {
+                Err(e @ CommandError::Spawn { .. }) => return Err(e.into()),
+                Err(e) => failures.push((frame.0, e.into())),
+                Ok(()) if is_nonempty_file(&frame.1).await => made[i] = true,
+                Ok(()) => failures.push((frame.0, anyhow!("no frame at {}s", frame.0))),
+            }
+        }
+    }
 
-        outputs.push(output);
+    if !made.contains(&true) {
+        return Ok(Thumbnails {
+            outputs: vec![],
+            failures,
+        });
     }
 
-    Ok(outputs)
+    let outputs: Vec<PathBuf> = (0..frames.len())
+        .map(|i| output_dir.join(format!("{}.webp", i)))
+        .collect();
+    for (i, (_, part)) in frames.iter().enumerate() {
+        if made[i] {
+            rename(part, &outputs[i]).await?;
+        }
+    }
+    for i in 0..frames.len() {
+        if made[i] {
+            continue;
+        }
+        // The thumbnail before it, or the first one there is if it's one of the first.
+        let neighbour = (0..i)
+            .rev()
+            .find(|j| made[*j])
+            .or_else(|| made.iter().position(|m| *m))
+            .unwrap();
+        copy(&outputs[neighbour], &outputs[i]).await?;
+    }
 
-    /*
-    let mut outputs = Vec::with_capacity(time_locations.len());
-    let mut futures = Vec::with_capacity(time_locations.len());
+    Ok(Thumbnails { outputs, failures })
+}
 
-    for loc in time_locations {
-        let output = output_dir.join(format!("{}.webp", loc));
+/// How far around a time location the thumbnail picker looks for a better frame.
+const THUMBNAIL_WINDOW_SECS: f64 = 20.0;
+
+/// A keyframe that could be used as a thumbnail, with the stats ffmpeg reported for it.
+#[derive(Debug, Default)]
+struct Candidate {
+    time: f64,
+    /// Average luma, from 16 (black) to 235 (white).
+    brightness: f64,
+    /// Normalised entropy of the edges in the frame, low for blurry or flat frames.
+    detail: f64,
+    /// Difference with the previous keyframe, from 0 to 1.
+    scene_change: f64,
+}
 
-        let mut handle = Command::new("ffmpeg")
-            .args(&[
-                "-ss",
-                &loc.to_string(),
-                "-i",
-                path.to_str().unwrap(),
-                "-frames:v",
-                "1",
-                output.to_str().unwrap(),
-            ])
-            .spawn()?;
+impl Candidate {
+    fn score(&self) -> f64 {
+        // Black and very dark frames are useless, regardless of what else is in them.
+        let brightness = ((self.brightness - 30.0) / 60.0).clamp(0.0, 1.0);
+        // Frames halfway through a cut or a fade are a mix of two scenes.
+        let stability = 1.0 - self.scene_change.clamp(0.0, 1.0);
+        brightness * (0.7 * self.detail + 0.3 * stability)
+    }
+}
 
-        outputs.push(output);
-        futures.push(handle.wait());
+/// Parse the output of ffmpeg's `metadata=print` filter into candidates.
+fn parse_frame_stats(output: &str, offset: f64) -> Vec<Candidate> {
+    let mut candidates: Vec<Candidate> = vec![];
+    for line in output.lines() {
+        if line.starts_with("frame:") {
+            let time = line
+                .split_whitespace()
+                .find_map(|field| field.strip_prefix("pts_time:"))
+                .and_then(|t| t.parse::<f64>().ok());
+            if let Some(time) = time {
+                candidates.push(Candidate {
+                    time: offset + time,
+                    ..Default::default()
+                });
+            }
+            continue;
+        }
+
+        let (Some(candidate), Some((key, value))) = (candidates.last_mut(), line.split_once('='))
+        else {
+            continue;
+        };
+        let Ok(value) = value.parse::<f64>() else {
+            continue;
+        };
+        match key {
+            "lavfi.scene_score" => candidate.scene_change = value,
+            "lavfi.signalstats.YAVG" => candidate.brightness = value,
+            "lavfi.entropy.normalized_entropy.normal.Y" => candidate.detail = value,
+            _ => {}
+        }
     }
+    candidates
+}
 
-    join_all(futures).await;
-    Ok(outputs)
-    */
+/// Find the best looking keyframe within [`THUMBNAIL_WINDOW_SECS`] of `location`.
+///
+/// Only keyframes are decoded, which is also all [`create_thumbnails`] can seek to.
+async fn pick_thumbnail_time(path: &Path, location: f64) -> Result<f64> {
+    let start = (location - THUMBNAIL_WINDOW_SECS).max(0.0);
+    let output = run_command(
+        Command::new("nice")
+            .args(["-n10", "ffmpeg", "-hide_banner", "-loglevel", "error"])
+            .args(["-skip_frame", "nokey", "-ss", &start.to_string()])
+            .args(["-t", &(location + THUMBNAIL_WINDOW_SECS - start).to_string()])
+            .arg("-i")
+            .arg(path.as_os_str())
+            .args([
+                "-an",
+                "-vf",
+                "scale=320:-2,select='gte(scene,0)',signalstats,edgedetect,entropy,metadata=print:file=-",
+                "-f",
+                "null",
+                "-",
+            ]),
+    )
+    .await?;
+
+    parse_frame_stats(&String::from_utf8_lossy(&output.stdout), start)
+        .into_iter()
+        .max_by(|a, b| a.score().total_cmp(&b.score()))
+        .map(|c| c.time)
+        .ok_or_else(|| {
+            anyhow!(
+                "no keyframes between {}s and {}s",
+                start,
+                location + THUMBNAIL_WINDOW_SECS
+            )
+        })
+}
+
+/// For every time location, pick a nearby frame that is likely to make a better thumbnail than
+/// the one at exactly that location, which is often a black screen or a "be right back" card.
+/// Locations for which this fails are kept as they are.
+pub async fn pick_thumbnail_times(path: &Path, locations: &[i32]) -> Vec<f64> {
+    let mut times = Vec::with_capacity(locations.len());
+    for location in locations {
+        let location = *location as f64;
+        match pick_thumbnail_time(path, location).await {
+            // Seeking without accuracy goes to the keyframe before the given time, so aim a bit
+            // past the printed timestamp to not land on the keyframe before it.
+            Ok(time) => times.push(time + 0.01),
+            Err(e) => {
+                eprintln!(
+                    "failed to pick thumbnail near {}s of {:?}, using it as is: {:?}",
+                    location, path, e
+                );
+                times.push(location);
+            }
+        }
+    }
+    times
 }
 
 /// Creates webp thumbnail for a clip
-pub async fn create_clip_thumbnail(path: &Path, output: &Path, begin: Duration) -> io::Result<()> {
+pub async fn create_clip_thumbnail(path: &Path, output: &Path, begin: Duration) -> Result<()> {
     create_dir_all(output.ancestors().nth(1).unwrap()).await?;
 
-    let mut handle = Command::new("ffmpeg")
-        .args(&[
-            "-hide_banner",
-            "-loglevel",
-            "error",
-            "-ss",
-            &begin.as_secs_f64().to_string(),
-            "-i",
-        ])
-        .arg(path.as_os_str())
-        .args(&["-frames:v", "1", "-y"])
-        .arg(output.as_os_str())
-        .spawn()?;
-    handle.wait().await?;
+    run_command(
+        Command::new("ffmpeg")
+            .args([
+                "-hide_banner",
+                "-loglevel",
+                "error",
+                "-ss",
+                &begin.as_secs_f64().to_string(),
+                "-i",
+            ])
+            .arg(path.as_os_str())
+            .args(["-frames:v", "1", "-y"])
+            .arg(output.as_os_str()),
+    )
+    .await?;
 
     Ok(())
 }
 
 /// Create a low-resolution av1 preview
-pub async fn create_preview(path: &Path, output: &Path, sections: &[(i32, i32)]) -> io::Result<()> {
+pub async fn create_preview(path: &Path, output: &Path, sections: &[(i32, i32)]) -> Result<()> {
     create_dir_all(output.ancestors().nth(1).unwrap()).await?;
 
     let path_string = path.as_os_str();
@@ -161,12 +424,11 @@ pub async fn create_preview(path: &Path, output: &Path, sections: &[(i32, i32)])
     ]);
     cmd.arg(output.as_os_str());
 
-    let mut handle = cmd.spawn()?;
-    handle.wait().await?;
+    run_command(&mut cmd).await?;
     Ok(())
 }
 /*
-pub async fn create_preview(path: &Path, output: &Path, sections: &[(i32, i32)]) -> io::Result<()> {
+pub async fn create_preview(path: &Path, output: &Path, sections: &[(i32, i32)]) -> Result<()> {
     create_dir_all(output.ancestors().nth(1).unwrap())?;
 
     let mut select_filter = String::new();
@@ -219,7 +481,7 @@ pub async fn create_clip_preview(
     output: &Path,
     begin: Duration,
     duration: Duration,
-) -> io::Result<()> {
+) -> Result<()> {
     create_dir_all(output.ancestors().nth(1).unwrap()).await?;
 
     let path_string = path.as_os_str();
@@ -258,7 +520,317 @@ pub async fn create_clip_preview(
     ]);
     cmd.arg(output.as_os_str());
 
-    let mut handle = cmd.spawn()?;
-    handle.wait().await?;
+    run_command(&mut cmd).await?;
+    Ok(())
+}
+
+/// Size of a single scrub thumbnail in the sprite sheets.
+pub const SCRUB_TILE_WIDTH: u32 = 160;
+pub const SCRUB_TILE_HEIGHT: u32 = 90;
+/// Scrub thumbnails per row and column of a sprite sheet.
+const SCRUB_SHEET_TILES: u32 = 10;
+
+/// Format a duration as a WebVTT timestamp.
+fn vtt_timestamp(secs: f64) -> String {
+    let millis = (secs * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+/// Create scrub thumbnails, one every [`SCRUB_PER_SECS`], tiled into sprite sheets
+/// `sprites/{n}.webp` in a single pass, and a WebVTT track `thumbnails.vtt` pointing every
+/// interval at its tile. Only keyframes are decoded, so the thumbnails are taken at the keyframe
+/// nearest to their time.
+pub async fn create_scrub_sprites(path: &Path, output_dir: &Path) -> Result<usize> {
+    let duration = get_video_duration(path).await?.as_secs_f64();
+    let count = ((duration / SCRUB_PER_SECS).ceil() as usize).max(1);
+
+    let sprites_dir = output_dir.join("sprites");
+    create_dir_all(&sprites_dir).await?;
+
+    let mut cmd = Command::new("nice");
+    cmd.args(["-n10", "ffmpeg", "-hide_banner", "-loglevel", "error"]);
+    cmd.args(["-skip_frame", "nokey", "-i"]);
+    cmd.arg(path.as_os_str());
+    cmd.arg("-vf");
+    cmd.arg(format!(
+        "fps=1/{per},scale={w}:{h}:force_original_aspect_ratio=decrease,pad={w}:{h}:(ow-iw)/2:(oh-ih)/2,tile={n}x{n}",
+        per = SCRUB_PER_SECS,
+        w = SCRUB_TILE_WIDTH,
+        h = SCRUB_TILE_HEIGHT,
+        n = SCRUB_SHEET_TILES,
+    ));
+    cmd.args([
+        "-an",
+        "-c:v",
+        "libwebp",
+        "-quality",
+        "70",
+        "-start_number",
+        "0",
+        "-y",
+    ]);
+    cmd.arg(sprites_dir.join("%d.webp").as_os_str());
+    run_command(&mut cmd).await?;
+
+    let per_sheet = (SCRUB_SHEET_TILES * SCRUB_SHEET_TILES) as usize;
+    let mut vtt = String::from("WEBVTT\n");
+    for i in 0..count {
+        let start = i as f64 * SCRUB_PER_SECS;
+        let end = (start + SCRUB_PER_SECS).min(duration);
+        let tile = (i % per_sheet) as u32;
+        vtt += &format!(
+            "\n{} --> {}\nsprites/{}.webp#xywh={},{},{},{}\n",
+            vtt_timestamp(start),
+            vtt_timestamp(end),
+            i / per_sheet,
+            tile % SCRUB_SHEET_TILES * SCRUB_TILE_WIDTH,
+            tile / SCRUB_SHEET_TILES * SCRUB_TILE_HEIGHT,
+            SCRUB_TILE_WIDTH,
+            SCRUB_TILE_HEIGHT,
+        );
+    }
+    write(output_dir.join("thumbnails.vtt"), vtt).await?;
+
+    Ok(count)
+}
+
+pub struct HlsRendition {
+    pub name: &'static str,
+    /// `None` for the audio-only rendition.
+    pub height: Option<u32>,
+    pub video_bitrate: &'static str,
+}
+
+/// Every rendition that is at most as high as the recording is generated, from high to low.
+pub const HLS_LADDER: &[HlsRendition] = &[
+    HlsRendition {
+        name: "1080p",
+        height: Some(1080),
+        video_bitrate: "5000k",
+    },
+    HlsRendition {
+        name: "720p",
+        height: Some(720),
+        video_bitrate: "2800k",
+    },
+    HlsRendition {
+        name: "480p",
+        height: Some(480),
+        video_bitrate: "1200k",
+    },
+    HlsRendition {
+        name: "audio",
+        height: None,
+        video_bitrate: "0k",
+    },
+];
+
+/// Create an HLS ladder with fragmented mp4 segments. Every rendition gets its own directory named
+/// after it in `output_dir`, next to the `master.m3u8` playlist. Without `has_audio` the video
+/// renditions are silent, and there can't be an audio-only one.
+pub async fn create_hls(
+    path: &Path,
+    output_dir: &Path,
+    renditions: &[&HlsRendition],
+    has_audio: bool,
+) -> Result<()> {
+    create_dir_all(output_dir).await?;
+
+    let mut cmd = Command::new("nice");
+    cmd.args(["-n10", "ffmpeg", "-hide_banner", "-loglevel", "error"]);
+    cmd.arg("-i");
+    cmd.arg(path.as_os_str());
+
+    let mut stream_map = vec![];
+    let mut video_index = 0;
+    for (audio_index, rendition) in renditions.iter().enumerate() {
+        match rendition.height {
+            Some(height) => {
+                cmd.args(["-map", "0:v:0", "-map", "0:a:0?"]);
+                cmd.arg(format!("-filter:v:{}", video_index));
+                cmd.arg(format!("scale=-2:{}", height));
+                cmd.arg(format!("-b:v:{}", video_index));
+                cmd.arg(rendition.video_bitrate);
+                cmd.arg(format!("-maxrate:v:{}", video_index));
+                cmd.arg(rendition.video_bitrate);
+
+                stream_map.push(if has_audio {
+                    format!(
+                        "v:{},a:{},name:{}",
+                        video_index, audio_index, rendition.name
+                    )
+                } else {
+                    format!("v:{},name:{}", video_index, rendition.name)
+                });
+                video_index += 1;
+            }
+            None if !has_audio => bail!("audio-only rendition of a recording without audio"),
+            None => {
+                cmd.args(["-map", "0:a:0"]);
+                stream_map.push(format!("a:{},name:{}", audio_index, rendition.name));
+            }
+        }
+    }
+
+    cmd.args([
+        "-c:v",
+        "libx264",
+        "-preset",
+        "veryfast",
+        "-bufsize",
+        "10000k",
+        // keyframes at every segment boundary, so all renditions can be switched between
+        "-force_key_frames",
+        &format!("expr:gte(t,n_forced*{})", HLS_SEGMENT_SECS),
+        "-c:a",
+        "aac",
+        "-b:a",
+        "128k",
+        "-ac",
+        "2",
+        "-f",
+        "hls",
+        "-hls_time",
+        &HLS_SEGMENT_SECS.to_string(),
+        "-hls_playlist_type",
+        "vod",
+        "-hls_segment_type",
+        "fmp4",
+        "-hls_fmp4_init_filename",
+        "init.mp4",
+        "-master_pl_name",
+        "master.m3u8",
+        "-var_stream_map",
+        &stream_map.join(" "),
+        "-hls_segment_filename",
+    ]);
+    cmd.arg(output_dir.join("%v").join("%05d.m4s").as_os_str());
+    cmd.arg("-y");
+    cmd.arg(output_dir.join("%v").join("index.m3u8").as_os_str());
+
+    run_command(&mut cmd).await?;
+    Ok(())
+}
+
+/// Escape `s` for use as an option value in a filter description passed to `-vf`, which is
+/// unescaped twice: once by the filter graph parser and once by the filter itself.
+pub fn escape_filter_value(s: &str) -> String {
+    fn escape(s: &str, special: &[char]) -> String {
+        let mut res = String::with_capacity(s.len());
+        for c in s.chars() {
+            if special.contains(&c) {
+                res.push('\\');
+            }
+            res.push(c);
+        }
+        res
+    }
+
+    escape(
+        &escape(s, &['\\', '\'', ':']),
+        &['\\', '\'', '[', ']', ',', ';'],
+    )
+}
+
+/// Cut a clip from the recording at full resolution, optionally with `title` burned into the
+/// top left corner and `chat` next to it. `height` is the height of the recording. The clip is
+/// re-encoded so that it starts exactly at `begin`, and written to a temporary file first so that
+/// `output` is never a partial export.
+pub async fn create_clip_export(
+    path: &Path,
+    output: &Path,
+    begin: Duration,
+    duration: Duration,
+    height: u32,
+    title: Option<&str>,
+    chat: Option<&ChatOverlay>,
+) -> Result<()> {
+    create_dir_all(output.ancestors().nth(1).unwrap()).await?;
+
+    let part = output.with_extension("mp4.part");
+    let filter_script = output.with_extension("mp4.filter");
+
+    let mut cmd = Command::new("nice");
+    cmd.args(["-n10", "ffmpeg", "-hide_banner", "-loglevel", "error"]);
+
+    cmd.arg("-ss");
+    cmd.arg(begin.as_secs_f64().to_string());
+    cmd.arg("-t");
+    cmd.arg(duration.as_secs_f64().to_string());
+    cmd.arg("-i");
+    cmd.arg(path.as_os_str());
+
+    let title_filter = title.map(|title| {
+        format!(
+            "drawtext=text={}:expansion=none:x={}:y={}:fontsize={}:fontcolor=white:box=1:boxcolor=black@0.5:boxborderw={}",
+            escape_filter_value(title),
+            height / 30,
+            height / 30,
+            height / 20,
+            height / 90,
+        )
+    });
+
+    match chat {
+        Some(chat) => {
+            for input in &chat.inputs {
+                cmd.args(["-loop", "1", "-i"]);
+                cmd.arg(input.as_os_str());
+            }
+
+            // The filter graph gets too long for the command line with a busy chat.
+            let filter = format!(
+                "[0:v]{}[base];{}",
+                title_filter.as_deref().unwrap_or("null"),
+                chat.filter
+            );
+            write(&filter_script, filter).await?;
+            cmd.arg("-filter_complex_script");
+            cmd.arg(filter_script.as_os_str());
+            cmd.args(["-map", "[v]", "-map", "0:a:0?"]);
+        }
+        None => {
+            cmd.args(["-map", "0:v:0", "-map", "0:a:0?"]);
+            if let Some(title_filter) = title_filter {
+                cmd.arg("-vf");
+                cmd.arg(title_filter);
+            }
+        }
+    }
+
+    cmd.args([
+        "-c:v",
+        "libx264",
+        "-preset",
+        "slow",
+        "-crf",
+        "18",
+        "-pix_fmt",
+        "yuv420p",
+        "-c:a",
+        "aac",
+        "-b:a",
+        "192k",
+        "-movflags",
+        "+faststart",
+        "-f",
+        "mp4",
+        "-y",
+    ]);
+    cmd.arg(part.as_os_str());
+
+    let res = run_command(&mut cmd).await;
+    if chat.is_some() {
+        let _ = remove_file(&filter_script).await;
+    }
+    res?;
+
+    rename(&part, output).await?;
     Ok(())
 }
@@ -1,11 +1,16 @@
+use crate::chat_index::ChatLine;
 use crate::create_preview::SCRUB_PER_SECS;
+use crate::highlights::Highlight;
+use crate::hype::{forget_cached_downsampled, HypeModel};
 use crate::loudness::LoudnessDatapoint;
+use crate::password::{hash_password, is_hash, verify_password};
 use crate::update_cache;
 use crate::util::timestamp;
 
 use streamwatch_shared::types::{
-    Clip, ConversionProgress, CreateClipRequest, DbMessage, GameInfo, GameItem, HypeDatapoint,
-    PersonInfo, StreamInfo, StreamJson, StreamProgress,
+    ChatSearchHit, Clip, ClipSuggestion, ConversionProgress, CreateClipRequest, DbMessage,
+    ExportStatus, GameInfo, GameItem, HypeDatapoint, PersonInfo, ProcessingOverride, Role,
+    StreamInfo, StreamJson, StreamProgress, User,
 };
 
 use std::borrow::BorrowMut;
@@ -22,14 +27,45 @@ use chrono::{DateTime, Utc};
 
 use futures::TryStreamExt;
 
+use uuid::Uuid;
+
+/// A row of `clip_suggestions`, the status is parsed by [`ClipSuggestionRow::parse`].
+struct ClipSuggestionRow {
+    id: i64,
+    stream_id: i64,
+    start_time: i64,
+    duration: i64,
+    confidence: f64,
+    title: Option<String>,
+    status: String,
+    clip_id: Option<i64>,
+    created_at: i64,
+}
+
+impl ClipSuggestionRow {
+    fn parse(self) -> Result<ClipSuggestion> {
+        Ok(ClipSuggestion {
+            id: self.id,
+            stream_id: self.stream_id,
+            start_time: Duration::from_millis(self.start_time as u64),
+            duration: Duration::from_millis(self.duration as u64),
+            confidence: self.confidence,
+            title: self.title,
+            status: self.status.parse()?,
+            clip_id: self.clip_id,
+            created_at: self.created_at,
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct Database {
     pub pool: sqlx::SqlitePool,
 }
 
 impl Database {
-    pub async fn new() -> Result<Self> {
-        let pool = SqlitePool::connect("sqlite:./db.db").await?;
+    pub async fn new(url: &str) -> Result<Self> {
+        let pool = SqlitePool::connect(url).await?;
         Ok(Self { pool })
     }
 
@@ -37,6 +73,7 @@ impl Database {
         StreamJson {
             info: StreamInfo {
                 id: row.get("id"),
+                library: row.get("library"),
                 title: row.get("title"),
                 title_type: row.get("title_type"),
                 file_name: {
@@ -65,8 +102,16 @@ impl Database {
                     let duration: f64 = row.get("duration");
                     (duration / SCRUB_PER_SECS) as usize
                 },
+                scrub_thumbnails_vtt: row
+                    .get::<bool, _>("has_scrub_sprites")
+                    .then(|| StreamInfo::scrub_thumbnails_vtt_url(row.get("id"))),
                 has_chat: row.get("has_chat"),
                 hype_average: row.get("hype_average"),
+                hls_renditions: {
+                    let json: Option<String> = row.get("hls_renditions");
+                    json.map(|json| serde_json::from_str(&json).unwrap())
+                        .unwrap_or_default()
+                },
             },
 
             persons: {
@@ -93,6 +138,15 @@ impl Database {
         }
     }
 
+    /// Users without a role are viewers.
+    fn make_user(id: i64, username: String, role: Option<String>) -> Result<User> {
+        let role = match role {
+            None => Role::Viewer,
+            Some(role) => role.parse()?,
+        };
+        Ok(User { id, username, role })
+    }
+
     pub async fn get_stream_by_id(
         conn: &mut SqliteConnection,
         stream_id: i64,
@@ -112,11 +166,14 @@ impl Database {
             preview_count,
             thumbnail_count,
             has_chat,
-            hype_average,
+            (SELECT hype_average FROM streams WHERE streams.id = streams_view.id) AS hype_average,
             datapoints,
             jumpcuts,
             persons,
-            games
+            games,
+            (SELECT library FROM streams WHERE streams.id = streams_view.id) AS library,
+            (SELECT hls_renditions FROM streams WHERE streams.id = streams_view.id) AS hls_renditions,
+            (SELECT has_scrub_sprites FROM streams WHERE streams.id = streams_view.id) AS has_scrub_sprites
         FROM streams_view
         WHERE id = ?
         LIMIT 1
@@ -131,9 +188,12 @@ impl Database {
         Ok(stream)
     }
 
-    pub async fn get_streams(conn: &mut SqliteConnection) -> Result<Vec<StreamJson>> {
+    pub async fn get_streams(
+        conn: &mut SqliteConnection,
+        library: Option<&str>,
+    ) -> Result<Vec<StreamJson>> {
         let instant = Instant::now();
-        let streams = sqlx::query(
+        let mut sql = String::from(
             r#"
         SELECT
             id,
@@ -147,17 +207,29 @@ impl Database {
             preview_count,
             thumbnail_count,
             has_chat,
-            hype_average,
+            (SELECT hype_average FROM streams WHERE streams.id = streams_view.id) AS hype_average,
             datapoints,
             jumpcuts,
             persons,
-            games
+            games,
+            (SELECT library FROM streams WHERE streams.id = streams_view.id) AS library,
+            (SELECT hls_renditions FROM streams WHERE streams.id = streams_view.id) AS hls_renditions,
+            (SELECT has_scrub_sprites FROM streams WHERE streams.id = streams_view.id) AS has_scrub_sprites
         FROM streams_view
         "#,
-        )
-        .map(Self::map_stream)
-        .fetch_all(conn.borrow_mut())
-        .await?;
+        );
+
+        let query = if let Some(library) = library {
+            sql += " WHERE id IN (SELECT id FROM streams WHERE library = ?)";
+            sqlx::query(&sql).bind(library)
+        } else {
+            sqlx::query(&sql)
+        };
+
+        let streams = query
+            .map(Self::map_stream)
+            .fetch_all(conn.borrow_mut())
+            .await?;
         println!("get_streams took {:?}", instant.elapsed());
 
         Ok(streams)
@@ -165,19 +237,30 @@ impl Database {
 
     pub async fn get_stream_id_by_filename(
         conn: &mut SqliteConnection,
+        library: &str,
         file_name: &str,
     ) -> Option<i64> {
-        sqlx::query!("SELECT id from streams where filename = ?1", file_name)
-            .map(|row| row.id)
-            .fetch_one(conn.borrow_mut())
-            .await
-            .ok()
+        sqlx::query!(
+            "SELECT id from streams where library = ?1 AND filename = ?2",
+            library,
+            file_name
+        )
+        .map(|row| row.id)
+        .fetch_one(conn.borrow_mut())
+        .await
+        .ok()
     }
 
     pub async fn remove_stream(conn: &mut SqliteConnection, stream_id: i64) -> Result<()> {
+        let mut tx = conn.begin().await?;
         sqlx::query!("DELETE FROM streams WHERE id = ?1", stream_id)
-            .execute(conn.borrow_mut())
+            .execute(tx.deref_mut())
+            .await?;
+        // chat_search can't reference streams, its rows are removed here
+        sqlx::query!("DELETE FROM chat_search WHERE stream_id = ?1", stream_id)
+            .execute(tx.deref_mut())
             .await?;
+        tx.commit().await?;
         update_cache().await?;
         Ok(())
     }
@@ -204,6 +287,91 @@ impl Database {
         Ok(items)
     }
 
+    pub async fn get_processing_overrides(
+        conn: &mut SqliteConnection,
+    ) -> Result<Vec<ProcessingOverride>> {
+        let items = sqlx::query!(
+            r#"
+            SELECT stream_id, kind, reason, inserted_at, inserted_by
+            FROM stream_processing_overrides
+            ORDER BY stream_id, kind
+            "#
+        )
+        .map(|row| ProcessingOverride {
+            stream_id: row.stream_id,
+            kind: row.kind,
+            reason: row.reason,
+            inserted_at: timestamp(row.inserted_at),
+            inserted_by: row.inserted_by,
+        })
+        .fetch_all(conn.borrow_mut())
+        .await?;
+        Ok(items)
+    }
+
+    /// Returns `Some(reason)` if jobs of the given kind are disabled for the stream.
+    pub async fn get_processing_override(
+        conn: &mut SqliteConnection,
+        stream_id: i64,
+        kind: &str,
+    ) -> Result<Option<Option<String>>> {
+        let reason = sqlx::query!(
+            "SELECT reason FROM stream_processing_overrides WHERE stream_id = ?1 AND kind = ?2",
+            stream_id,
+            kind,
+        )
+        .map(|row| row.reason)
+        .fetch_optional(conn.borrow_mut())
+        .await?;
+        Ok(reason)
+    }
+
+    pub async fn set_processing_override(
+        conn: &mut SqliteConnection,
+        user_id: i64,
+        stream_id: i64,
+        kind: &str,
+        reason: Option<String>,
+    ) -> Result<()> {
+        let inserted_at = Utc::now().timestamp();
+        sqlx::query!(
+            r#"
+            INSERT INTO stream_processing_overrides
+                (stream_id, kind, reason, inserted_at, inserted_by)
+            VALUES
+                (?1, ?2, ?3, ?4, ?5)
+            ON CONFLICT DO UPDATE SET
+                reason = ?3,
+                inserted_at = ?4,
+                inserted_by = ?5
+            "#,
+            stream_id,
+            kind,
+            reason,
+            inserted_at,
+            user_id,
+        )
+        .execute(conn.borrow_mut())
+        .await?;
+        Ok(())
+    }
+
+    /// Returns false if there was no such override.
+    pub async fn remove_processing_override(
+        conn: &mut SqliteConnection,
+        stream_id: i64,
+        kind: &str,
+    ) -> Result<bool> {
+        let res = sqlx::query!(
+            "DELETE FROM stream_processing_overrides WHERE stream_id = ?1 AND kind = ?2",
+            stream_id,
+            kind,
+        )
+        .execute(conn.borrow_mut())
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
     pub async fn get_possible_games(conn: &mut SqliteConnection) -> Result<Vec<GameInfo>> {
         let res = sqlx::query!("SELECT id,name,platform,twitch_name FROM games ORDER BY name")
             .map(|row| GameInfo {
@@ -216,19 +384,22 @@ impl Database {
             .await?;
         Ok(res)
     }
+    /// `user_id` is the user who added the game, `None` if it was found in the chat metadata.
     pub async fn insert_possible_game<'c>(
         conn: &mut SqliteConnection,
+        user_id: Option<i64>,
         name: String,
         twitch_name: Option<String>,
         platform: Option<String>,
     ) -> Result<GameInfo> {
         let inserted_at = Utc::now().timestamp();
         let res = sqlx::query!(
-            "INSERT INTO games(name, platform, twitch_name, inserted_at) VALUES(?1, ?2, ?3, ?4)",
+            "INSERT INTO games(name, platform, twitch_name, inserted_at, inserted_by) VALUES(?1, ?2, ?3, ?4, ?5)",
             name,
             platform,
             twitch_name,
-            inserted_at
+            inserted_at,
+            user_id,
         )
         .execute(conn)
         .await?;
@@ -240,8 +411,10 @@ impl Database {
         })
     }
 
+    /// `user_id` is the user who made the change, `None` if it was found in the chat metadata.
     pub async fn replace_games<I>(
         conn: &mut SqliteConnection,
+        user_id: Option<i64>,
         stream_id: i64,
         items: I,
     ) -> Result<()>
@@ -259,11 +432,12 @@ impl Database {
             let start_time = item.start_time.as_secs_f64();
 
             sqlx::query!(
-                "INSERT INTO game_features(stream_id, game_id, start_time, inserted_at) VALUES(?1, ?2, ?3, ?4)",
+                "INSERT INTO game_features(stream_id, game_id, start_time, inserted_at, inserted_by) VALUES(?1, ?2, ?3, ?4, ?5)",
                 stream_id,
                 item.id,
                 start_time,
                 real_time,
+                user_id,
             )
             .execute(tx.deref_mut())
             .await?;
@@ -283,6 +457,7 @@ impl Database {
     }
     pub async fn replace_persons(
         conn: &mut SqliteConnection,
+        user_id: i64,
         stream_id: i64,
         person_ids: Vec<i64>,
     ) -> Result<()> {
@@ -298,10 +473,11 @@ impl Database {
 
         for id in person_ids {
             sqlx::query!(
-                "INSERT INTO person_participations(stream_id, person_id, inserted_at) VALUES(?1, ?2, ?3)",
+                "INSERT INTO person_participations(stream_id, person_id, inserted_at, inserted_by) VALUES(?1, ?2, ?3, ?4)",
                 stream_id,
                 id,
                 real_time,
+                user_id,
             )
             .execute(tx.deref_mut())
             .await?;
@@ -313,7 +489,13 @@ impl Database {
         Ok(())
     }
 
-    pub async fn signup(conn: &mut SqliteConnection, username: &str, password: &str) -> Result<()> {
+    pub async fn signup(
+        conn: &mut SqliteConnection,
+        username: &str,
+        password: &str,
+    ) -> Result<User> {
+        let hash = hash_password(password.to_owned()).await?;
+
         let mut tx = conn.begin().await?;
 
         let username_taken = Self::get_userid_by_username(tx.borrow_mut(), username)
@@ -325,7 +507,7 @@ impl Database {
 
         let created_at = Utc::now().timestamp();
 
-        sqlx::query!(
+        let res = sqlx::query!(
             r#"
             INSERT INTO users
                 (username, password, inserted_at)
@@ -333,13 +515,57 @@ impl Database {
                 (?1, ?2, ?3)
             "#,
             username,
-            password,
+            hash,
             created_at,
         )
         .execute(tx.deref_mut())
         .await?;
 
         tx.commit().await?;
+        Ok(User {
+            id: res.last_insert_rowid(),
+            username: username.to_owned(),
+            role: Role::Viewer,
+        })
+    }
+
+    pub async fn get_user(conn: &mut SqliteConnection, user_id: i64) -> Result<Option<User>> {
+        let row = sqlx::query!(
+            r#"
+            SELECT users.id, users.username, roles.role AS "role?"
+            FROM users
+            LEFT JOIN roles
+                ON roles.user_id = users.id
+            WHERE users.id = ?1
+            "#,
+            user_id,
+        )
+        .fetch_optional(conn.borrow_mut())
+        .await?;
+        row.map(|row| Self::make_user(row.id, row.username, row.role))
+            .transpose()
+    }
+
+    pub async fn set_user_role(
+        conn: &mut SqliteConnection,
+        user_id: i64,
+        role: Role,
+    ) -> Result<()> {
+        let role = role.as_str();
+        sqlx::query!(
+            r#"
+            INSERT INTO roles
+                (user_id, role)
+            VALUES
+                (?1, ?2)
+            ON CONFLICT DO UPDATE SET
+                role = ?2
+            "#,
+            user_id,
+            role,
+        )
+        .execute(conn.borrow_mut())
+        .await?;
         Ok(())
     }
 
@@ -354,6 +580,9 @@ impl Database {
         Ok(res)
     }
 
+    /// Check the password of the given user. Users that still have a plaintext password get it
+    /// replaced by a hash when they log in with the correct password. Users without a password
+    /// can't log in.
     pub async fn check_password(
         conn: &mut SqliteConnection,
         user_id: i64,
@@ -365,7 +594,102 @@ impl Database {
                 .fetch_one(conn.borrow_mut())
                 .await?;
 
-        Ok(db_pass.map(|db_pass| password == db_pass).unwrap_or(true))
+        let db_pass = match db_pass {
+            None => return Ok(false),
+            Some(p) => p,
+        };
+
+        if is_hash(&db_pass) {
+            return verify_password(password.to_owned(), db_pass).await;
+        }
+
+        if password != db_pass {
+            return Ok(false);
+        }
+
+        let hash = hash_password(password.to_owned()).await?;
+        sqlx::query!(
+            "UPDATE users SET password = ?1 WHERE id = ?2",
+            hash,
+            user_id
+        )
+        .execute(conn.borrow_mut())
+        .await?;
+        println!("[{}] rehashed plaintext password", user_id);
+
+        Ok(true)
+    }
+
+    /// Create a new session for the user, returning the session token and when it expires.
+    pub async fn create_session(
+        conn: &mut SqliteConnection,
+        user_id: i64,
+        lifetime: chrono::Duration,
+    ) -> Result<(String, DateTime<Utc>)> {
+        let now = Utc::now();
+        let expires_at = now + lifetime;
+        let token = Uuid::new_v4().simple().to_string();
+
+        let created_at = now.timestamp();
+        let expires_at_ts = expires_at.timestamp();
+
+        let mut tx = conn.begin().await?;
+
+        sqlx::query!("DELETE FROM sessions WHERE expires_at <= ?1", created_at)
+            .execute(tx.deref_mut())
+            .await?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO sessions
+                (token, user_id, created_at, expires_at)
+            VALUES
+                (?1, ?2, ?3, ?4)
+            "#,
+            token,
+            user_id,
+            created_at,
+            expires_at_ts,
+        )
+        .execute(tx.deref_mut())
+        .await?;
+
+        tx.commit().await?;
+        Ok((token, expires_at))
+    }
+
+    /// Get the user the session token belongs to, if it is valid and not expired.
+    pub async fn get_session_user(
+        conn: &mut SqliteConnection,
+        token: &str,
+    ) -> Result<Option<User>> {
+        let now = Utc::now().timestamp();
+
+        let row = sqlx::query!(
+            r#"
+            SELECT users.id, users.username, roles.role AS "role?"
+            FROM sessions
+            JOIN users
+                ON users.id = sessions.user_id
+            LEFT JOIN roles
+                ON roles.user_id = users.id
+            WHERE sessions.token = ?1
+                AND sessions.expires_at > ?2
+            "#,
+            token,
+            now,
+        )
+        .fetch_optional(conn.borrow_mut())
+        .await?;
+        row.map(|row| Self::make_user(row.id, row.username, row.role))
+            .transpose()
+    }
+
+    pub async fn remove_session(conn: &mut SqliteConnection, token: &str) -> Result<()> {
+        sqlx::query!("DELETE FROM sessions WHERE token = ?1", token)
+            .execute(conn.borrow_mut())
+            .await?;
+        Ok(())
     }
 
     pub async fn get_streams_progress(
@@ -444,7 +768,8 @@ impl Database {
         start: DateTime<Utc>,
         end: DateTime<Utc>,
     ) -> Result<Vec<DbMessage>> {
-        let start = start.timestamp();
+        // messages are stored in whole seconds, a message at 10 isn't between 10.5 and 11.5.
+        let start = start.timestamp() + i64::from(start.timestamp_subsec_nanos() > 0);
         let end = end.timestamp();
 
         let query = sqlx::query(
@@ -461,7 +786,8 @@ impl Database {
                 ON users.id = author_id
             WHERE stream_id = ?
                 AND ? <= time
-                AND time <= ?;
+                AND time <= ?
+            ORDER BY time, messages.id;
             "#,
         )
         .bind(stream_id)
@@ -482,6 +808,43 @@ impl Database {
         Ok(items)
     }
 
+    pub async fn add_message(
+        conn: &mut SqliteConnection,
+        stream_id: i64,
+        author: &User,
+        time: DateTime<Utc>,
+        message: String,
+    ) -> Result<DbMessage> {
+        let real_time = Utc::now();
+        let time_ts = time.timestamp();
+        let real_time_ts = real_time.timestamp();
+
+        let res = sqlx::query!(
+            r#"
+            INSERT INTO messages
+                (stream_id, author_id, time, real_time, content)
+            VALUES
+                (?1, ?2, ?3, ?4, ?5)
+            "#,
+            stream_id,
+            author.id,
+            time_ts,
+            real_time_ts,
+            message,
+        )
+        .execute(conn.borrow_mut())
+        .await?;
+
+        Ok(DbMessage {
+            id: res.last_insert_rowid(),
+            author_id: author.id,
+            message,
+            time: timestamp(time_ts),
+            real_time: timestamp(real_time_ts),
+            author_name: author.username.clone(),
+        })
+    }
+
     pub async fn get_ratings(
         conn: &mut SqliteConnection,
         user_id: i64,
@@ -541,6 +904,7 @@ impl Database {
 
     pub async fn set_custom_stream_title(
         conn: &mut SqliteConnection,
+        user_id: i64,
         stream_id: i64,
         title: String,
     ) -> Result<()> {
@@ -557,16 +921,18 @@ impl Database {
             sqlx::query!(
                 r#"
                 INSERT INTO custom_stream_titles
-                    (stream_id, title, inserted_at)
+                    (stream_id, title, inserted_at, inserted_by)
                 VALUES
-                    (?1, ?2, ?3)
+                    (?1, ?2, ?3, ?4)
                 ON CONFLICT DO UPDATE SET
                     title = ?2,
-                    inserted_at = ?3
+                    inserted_at = ?3,
+                    inserted_by = ?4
                 "#,
                 stream_id,
                 title,
-                inserted_at
+                inserted_at,
+                user_id,
             )
             .execute(conn.borrow_mut())
             .await?;
@@ -576,34 +942,80 @@ impl Database {
         Ok(())
     }
 
+    /// The loudness and chat speed of every second of a stream, with the hype computed by
+    /// `model`.
     pub async fn get_hype_datapoints(
         conn: &mut SqliteConnection,
         stream_id: i64,
+        model: &HypeModel,
     ) -> Result<Vec<HypeDatapoint>> {
-        let res = sqlx::query(
-            "SELECT ts,loudness,messages FROM stream_hype_datapoints_sad WHERE stream_id = ?",
+        // If only sqlite supported FULL OUTER JOIN...
+        let mut res = sqlx::query!(
+            r#"
+            SELECT
+                dp.ts AS "ts!: i64",
+                loudness.momentary AS "loudness?: f64",
+                chat.messages AS "messages?: i32"
+            FROM (
+                SELECT ts FROM stream_loudness WHERE stream_id = ?1
+                UNION
+                SELECT ts FROM stream_chatspeed_datapoints WHERE stream_id = ?1
+            ) AS dp
+            LEFT JOIN stream_loudness AS loudness
+                ON loudness.stream_id = ?1 AND loudness.ts = dp.ts
+            LEFT JOIN stream_chatspeed_datapoints AS chat
+                ON chat.stream_id = ?1 AND chat.ts = dp.ts
+            "#,
+            stream_id,
         )
-        .bind(stream_id)
-        .map(|row: SqliteRow| HypeDatapoint {
-            ts: timestamp(row.get("ts")),
-            loudness: row.get("loudness"),
-            chat_hype: row.get("messages"),
-            hype: {
-                let loudness: Option<f64> = row.get("loudness");
-                let messages: Option<i32> = row.get("messages");
-
-                loudness
-                    .map(|m| 1.0 / 8.0 * (1.0 + (5.0 * (m + 75.0 / 2.0) / 80.0).tanh()))
-                    .unwrap_or(0.0)
-                    + messages.map(|m| (m as f64) / 5.0).unwrap_or(0.0)
-            },
+        .map(|row| HypeDatapoint {
+            ts: timestamp(row.ts),
+            loudness: row.loudness,
+            chat_hype: row.messages,
+            hype: 0.0,
         })
         .fetch_all(conn.borrow_mut())
         .await?;
 
+        model.apply(&mut res);
+
         Ok(res)
     }
 
+    pub async fn get_meta(conn: &mut SqliteConnection, key: &str) -> Result<Option<String>> {
+        let value = sqlx::query!("SELECT value FROM meta WHERE key = ?1", key)
+            .map(|row| row.value)
+            .fetch_optional(conn.borrow_mut())
+            .await?;
+        Ok(value.flatten())
+    }
+
+    pub async fn set_meta(conn: &mut SqliteConnection, key: &str, value: &str) -> Result<()> {
+        sqlx::query!(
+            "INSERT OR REPLACE INTO meta (key, value) VALUES (?1, ?2)",
+            key,
+            value
+        )
+        .execute(conn.borrow_mut())
+        .await?;
+        Ok(())
+    }
+
+    pub async fn set_hype_average(
+        conn: &mut SqliteConnection,
+        stream_id: i64,
+        hype_average: Option<f64>,
+    ) -> Result<()> {
+        sqlx::query!(
+            "UPDATE streams SET hype_average = ?1 WHERE id = ?2",
+            hype_average,
+            stream_id
+        )
+        .execute(conn.borrow_mut())
+        .await?;
+        Ok(())
+    }
+
     pub async fn set_stream_decibels(
         conn: &mut SqliteConnection,
         stream_id: i64,
@@ -668,6 +1080,7 @@ impl Database {
         tx.commit().await?;
 
         update_cache().await?;
+        forget_cached_downsampled(stream_id).await;
         Ok(())
     }
 
@@ -704,9 +1117,111 @@ impl Database {
         tx.commit().await?;
 
         update_cache().await?;
+        forget_cached_downsampled(stream_id).await;
+        Ok(())
+    }
+
+    /// Replace the chat of a stream in the search index.
+    pub async fn set_chat_search_lines(
+        conn: &mut SqliteConnection,
+        stream_id: i64,
+        lines: Vec<ChatLine>,
+    ) -> Result<()> {
+        let mut tx = conn.begin().await?;
+
+        sqlx::query!("DELETE FROM chat_search WHERE stream_id = ?1", stream_id)
+            .execute(tx.deref_mut())
+            .await?;
+
+        for line in lines {
+            let ts = line.ts.timestamp_millis();
+            sqlx::query!(
+                "INSERT INTO chat_search(text, author, stream_id, ts) VALUES(?1, ?2, ?3, ?4)",
+                line.text,
+                line.author,
+                stream_id,
+                ts,
+            )
+            .execute(tx.deref_mut())
+            .await?;
+        }
+
+        sqlx::query!(
+            "UPDATE streams SET chat_indexed = 1 WHERE id = ?1",
+            stream_id
+        )
+        .execute(tx.deref_mut())
+        .await?;
+
+        tx.commit().await?;
+
         Ok(())
     }
 
+    /// Streams with chat that isn't in the search index yet.
+    pub async fn get_streams_without_chat_index(conn: &mut SqliteConnection) -> Result<Vec<i64>> {
+        let ids = sqlx::query!("SELECT id FROM streams WHERE has_chat = 1 AND chat_indexed = 0")
+            .map(|row| row.id)
+            .fetch_all(conn.borrow_mut())
+            .await?;
+        Ok(ids)
+    }
+
+    /// Chat messages containing `phrase`, newest first.
+    pub async fn search_chat(
+        conn: &mut SqliteConnection,
+        phrase: &str,
+        stream_id: Option<i64>,
+        limit: i64,
+        skip: i64,
+    ) -> Result<Vec<ChatSearchHit>> {
+        // Quoted, so the phrase isn't parsed as an FTS5 query.
+        let query = format!("\"{}\"", phrase.replace('"', "\"\""));
+
+        let hits = sqlx::query!(
+            r#"
+            SELECT
+                chat_search.stream_id AS "stream_id!: i64",
+                chat_search.ts AS "ts!: i64",
+                chat_search.author AS "author!: String",
+                chat_search.text AS "text!: String",
+                streams.ts AS stream_ts
+            FROM chat_search
+            JOIN streams
+                ON streams.id = chat_search.stream_id
+            WHERE chat_search MATCH ?1 AND (?2 IS NULL OR chat_search.stream_id = ?2)
+            ORDER BY chat_search.ts DESC
+            LIMIT ?3 OFFSET ?4
+            "#,
+            query,
+            stream_id,
+            limit,
+            skip,
+        )
+        .map(|row| ChatSearchHit {
+            stream_id: row.stream_id,
+            ts: timestamp(row.ts / 1000),
+            offset: Duration::from_millis((row.ts - row.stream_ts * 1000).max(0) as u64),
+            author: row.author,
+            text: row.text,
+        })
+        .fetch_all(conn.borrow_mut())
+        .await?;
+
+        Ok(hits)
+    }
+
+    /// The export status of a clip, from the time it was last exported and the status of the
+    /// latest export job.
+    fn export_status(exported_at: Option<i64>, job_status: Option<&str>) -> ExportStatus {
+        match job_status {
+            Some("queued" | "running") => ExportStatus::Pending,
+            _ if exported_at.is_some() => ExportStatus::Ready,
+            Some("failed") => ExportStatus::Failed,
+            _ => ExportStatus::None,
+        }
+    }
+
     pub async fn get_clips(
         conn: &mut SqliteConnection,
         stream_id: Option<i64>,
@@ -716,7 +1231,14 @@ impl Database {
                 SELECT
                     clips.*,
                     users.username,
-                    (SELECT COUNT(*) FROM clip_views WHERE clip_id=clips.id) AS view_count
+                    (SELECT COUNT(*) FROM clip_views WHERE clip_id=clips.id) AS view_count,
+                    (
+                        SELECT status
+                        FROM jobs
+                        WHERE kind='clip_export' AND jobs.clip_id=clips.id
+                        ORDER BY id DESC
+                        LIMIT 1
+                    ) AS export_job_status
                 FROM clips
                 JOIN users
                     ON users.id=clips.author_id
@@ -741,15 +1263,70 @@ impl Database {
                 title: row.get("title"),
                 created_at: row.get("created_at"),
                 view_count: row.get("view_count"),
+                export_status: Self::export_status(
+                    row.get("exported_at"),
+                    row.get("export_job_status"),
+                ),
+                export_has_title: row.get("export_has_title"),
+                export_has_chat: row.get("export_has_chat"),
             })
             .fetch_all(conn.borrow_mut())
             .await?;
         Ok(items)
     }
 
+    pub async fn get_clip_by_id(conn: &mut SqliteConnection, id: i64) -> Result<Option<Clip>> {
+        let row = sqlx::query!(
+            r#"
+            SELECT
+                clips.id,
+                clips.author_id,
+                users.username,
+                clips.stream_id,
+                clips.start_time,
+                clips.duration,
+                clips.title,
+                clips.created_at,
+                clips.exported_at,
+                clips.export_has_title,
+                clips.export_has_chat,
+                (SELECT COUNT(*) FROM clip_views WHERE clip_id=clips.id) AS "view_count!: i64",
+                (
+                    SELECT status
+                    FROM jobs
+                    WHERE kind='clip_export' AND jobs.clip_id=clips.id
+                    ORDER BY id DESC
+                    LIMIT 1
+                ) AS "export_job_status?: String"
+            FROM clips
+            JOIN users
+                ON users.id=clips.author_id
+            WHERE clips.id = ?1
+            "#,
+            id
+        )
+        .fetch_optional(conn.borrow_mut())
+        .await?;
+
+        Ok(row.map(|row| Clip {
+            id: row.id,
+            author_id: row.author_id,
+            author_username: row.username,
+            stream_id: row.stream_id,
+            start_time: Duration::from_millis(row.start_time as u64),
+            duration: Duration::from_millis(row.duration as u64),
+            title: row.title,
+            created_at: row.created_at,
+            view_count: row.view_count,
+            export_status: Self::export_status(row.exported_at, row.export_job_status.as_deref()),
+            export_has_title: row.export_has_title != 0,
+            export_has_chat: row.export_has_chat != 0,
+        }))
+    }
+
     pub async fn create_clip(
         conn: &mut SqliteConnection,
-        author_id: i64,
+        author: &User,
         clip_request: CreateClipRequest,
     ) -> Result<Clip> {
         let created_at = Utc::now().timestamp();
@@ -763,7 +1340,7 @@ impl Database {
             VALUES
                 (?1, ?2, ?3, ?4, ?5, ?6)
             "#,
-            author_id,
+            author.id,
             clip_request.stream_id,
             start_time,
             duration,
@@ -775,14 +1352,17 @@ impl Database {
 
         Ok(Clip {
             id: res.last_insert_rowid(),
-            author_id,
-            author_username: clip_request.author_username,
+            author_id: author.id,
+            author_username: author.username.clone(),
             stream_id: clip_request.stream_id,
             start_time: clip_request.start_time,
             duration: clip_request.duration,
             title: clip_request.title,
             created_at,
             view_count: 0, // we just created the clip, so view_count=0 is always valid.
+            export_status: ExportStatus::None,
+            export_has_title: false,
+            export_has_chat: false,
         })
     }
 
@@ -801,7 +1381,8 @@ impl Database {
             SET
                 start_time=?1,
                 duration=?2,
-                title=?3
+                title=?3,
+                exported_at=NULL
             WHERE
                 id=?4 AND author_id=?5
             "#,
@@ -817,6 +1398,180 @@ impl Database {
         Ok(res.rows_affected() > 0)
     }
 
+    pub async fn set_clip_exported(
+        conn: &mut SqliteConnection,
+        clip_id: i64,
+        has_title: bool,
+        has_chat: bool,
+    ) -> Result<()> {
+        let now = Utc::now().timestamp();
+        sqlx::query!(
+            "UPDATE clips SET exported_at = ?1, export_has_title = ?2, export_has_chat = ?3 WHERE id = ?4",
+            now,
+            has_title,
+            has_chat,
+            clip_id,
+        )
+        .execute(conn.borrow_mut())
+        .await?;
+        Ok(())
+    }
+
+    /// The open clip suggestions of a stream, or of all streams, best first.
+    pub async fn get_clip_suggestions(
+        conn: &mut SqliteConnection,
+        stream_id: Option<i64>,
+    ) -> Result<Vec<ClipSuggestion>> {
+        let rows = sqlx::query_as!(
+            ClipSuggestionRow,
+            r#"
+            SELECT *
+            FROM clip_suggestions
+            WHERE status = 'open' AND (?1 IS NULL OR stream_id = ?1)
+            ORDER BY confidence DESC
+            "#,
+            stream_id,
+        )
+        .fetch_all(conn.borrow_mut())
+        .await?;
+        rows.into_iter().map(ClipSuggestionRow::parse).collect()
+    }
+
+    /// Replace the open suggestions of a stream with newly detected highlights. Highlights that
+    /// overlap with a suggestion that was already accepted or dismissed are left out, so they
+    /// don't come back every time the detector runs.
+    pub async fn set_clip_suggestions(
+        conn: &mut SqliteConnection,
+        stream_id: i64,
+        highlights: &[Highlight],
+    ) -> Result<()> {
+        let mut tx = conn.begin().await?;
+
+        sqlx::query!(
+            "DELETE FROM clip_suggestions WHERE stream_id = ?1 AND status = 'open'",
+            stream_id
+        )
+        .execute(tx.deref_mut())
+        .await?;
+
+        let now = Utc::now().timestamp();
+        for highlight in highlights {
+            let start_time = highlight.start_time.as_millis() as i64;
+            let duration = highlight.duration.as_millis() as i64;
+            let end_time = (highlight.start_time + highlight.duration).as_millis() as i64;
+
+            sqlx::query!(
+                r#"
+                INSERT INTO clip_suggestions
+                    (stream_id, start_time, duration, confidence, title, created_at)
+                SELECT ?1, ?2, ?3, ?4, ?5, ?6
+                WHERE NOT EXISTS (
+                    SELECT 1
+                    FROM clip_suggestions
+                    WHERE stream_id = ?1 AND start_time < ?7 AND start_time + duration > ?2
+                )
+                "#,
+                stream_id,
+                start_time,
+                duration,
+                highlight.confidence,
+                highlight.title,
+                now,
+                end_time,
+            )
+            .execute(tx.deref_mut())
+            .await?;
+        }
+
+        sqlx::query!(
+            "UPDATE streams SET highlights_detected = 1 WHERE id = ?1",
+            stream_id
+        )
+        .execute(tx.deref_mut())
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Have the highlight detector run again on every stream.
+    pub async fn reset_highlights_detected(conn: &mut SqliteConnection) -> Result<()> {
+        sqlx::query!("UPDATE streams SET highlights_detected = 0")
+            .execute(conn.borrow_mut())
+            .await?;
+        Ok(())
+    }
+
+    /// Streams the highlight detector hasn't run on yet.
+    pub async fn get_streams_without_highlights(conn: &mut SqliteConnection) -> Result<Vec<i64>> {
+        let ids = sqlx::query!("SELECT id FROM streams WHERE highlights_detected = 0")
+            .map(|row| row.id)
+            .fetch_all(conn.borrow_mut())
+            .await?;
+        Ok(ids)
+    }
+
+    /// Turn an open suggestion into a clip by `author`. Returns `None` if there is no open
+    /// suggestion with this id.
+    pub async fn accept_clip_suggestion(
+        conn: &mut SqliteConnection,
+        author: &User,
+        suggestion_id: i64,
+    ) -> Result<Option<Clip>> {
+        let mut tx = conn.begin().await?;
+
+        let suggestion = sqlx::query_as!(
+            ClipSuggestionRow,
+            "SELECT * FROM clip_suggestions WHERE id = ?1 AND status = 'open'",
+            suggestion_id,
+        )
+        .fetch_optional(tx.deref_mut())
+        .await?;
+        let suggestion = match suggestion {
+            None => return Ok(None),
+            Some(s) => s.parse()?,
+        };
+
+        let clip = Self::create_clip(
+            tx.deref_mut(),
+            author,
+            CreateClipRequest {
+                stream_id: suggestion.stream_id,
+                start_time: suggestion.start_time,
+                duration: suggestion.duration,
+                title: suggestion.title,
+            },
+        )
+        .await?;
+
+        sqlx::query!(
+            "UPDATE clip_suggestions SET status = 'accepted', clip_id = ?1 WHERE id = ?2",
+            clip.id,
+            suggestion_id
+        )
+        .execute(tx.deref_mut())
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(Some(clip))
+    }
+
+    /// Returns whether there was an open suggestion with this id.
+    pub async fn dismiss_clip_suggestion(
+        conn: &mut SqliteConnection,
+        suggestion_id: i64,
+    ) -> Result<bool> {
+        let res = sqlx::query!(
+            "UPDATE clip_suggestions SET status = 'dismissed' WHERE id = ?1 AND status = 'open'",
+            suggestion_id,
+        )
+        .execute(conn.borrow_mut())
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
     pub async fn add_clip_view(
         conn: &mut SqliteConnection,
         clip_id: i64,
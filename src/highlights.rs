@@ -0,0 +1,267 @@
+use crate::chat::FileReader;
+
+use streamwatch_shared::types::{HypeDatapoint, StreamInfo};
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use serde_json::Value;
+
+use anyhow::Result;
+
+/// Hype is averaged over this many seconds before looking for highlights, so a single loud
+/// second doesn't count.
+const WINDOW_SECS: usize = 15;
+/// How many standard deviations the averaged hype has to be above the stream's average.
+const Z_THRESHOLD: f64 = 2.0;
+/// Hype has to stay above the threshold for at least this long.
+const MIN_SUSTAIN_SECS: usize = 5;
+/// Clips start this long before the hype goes up, chat reacts late and the build-up is part of
+/// the moment.
+const PRE_ROLL_SECS: usize = 15;
+const POST_ROLL_SECS: usize = 5;
+const MIN_CLIP_SECS: usize = 20;
+const MAX_CLIP_SECS: usize = 60;
+/// Only the best highlights of a stream are suggested.
+const MAX_HIGHLIGHTS: usize = 20;
+
+/// A chat message or word has to be said this many times to become the title.
+const MIN_TITLE_REPEATS: usize = 3;
+const MAX_TITLE_LENGTH: usize = 60;
+
+/// A part of a stream that is probably worth clipping.
+#[derive(Clone, Debug)]
+pub struct Highlight {
+    pub start_time: Duration,
+    pub duration: Duration,
+    /// From 0 to 1.
+    pub confidence: f64,
+    pub title: Option<String>,
+}
+
+/// A stretch of seconds where the averaged hype is above the threshold.
+struct Region {
+    start: usize,
+    end: usize,
+    peak: usize,
+    peak_z: f64,
+}
+
+/// Find the stretches where the averaged hype stays well above the average of the stream.
+fn find_regions(hype: &[f64]) -> Vec<Region> {
+    let mut sums = vec![0.0; hype.len() + 1];
+    for (i, h) in hype.iter().enumerate() {
+        sums[i + 1] = sums[i] + h;
+    }
+    let smoothed: Vec<f64> = (0..hype.len())
+        .map(|i| {
+            let start = i.saturating_sub(WINDOW_SECS / 2);
+            let end = (i + WINDOW_SECS / 2 + 1).min(hype.len());
+            (sums[end] - sums[start]) / (end - start) as f64
+        })
+        .collect();
+
+    let n = smoothed.len() as f64;
+    let mean = smoothed.iter().sum::<f64>() / n;
+    let sd = (smoothed.iter().map(|h| (h - mean).powi(2)).sum::<f64>() / n).sqrt();
+    if sd == 0.0 {
+        return vec![];
+    }
+
+    let mut regions = vec![];
+    let mut current: Option<Region> = None;
+    for (i, h) in smoothed.iter().enumerate() {
+        let z = (h - mean) / sd;
+        if z > Z_THRESHOLD {
+            let region = current.get_or_insert(Region {
+                start: i,
+                end: i,
+                peak: i,
+                peak_z: z,
+            });
+            region.end = i + 1;
+            if z > region.peak_z {
+                region.peak = i;
+                region.peak_z = z;
+            }
+        } else if let Some(region) = current.take() {
+            regions.push(region);
+        }
+    }
+    regions.extend(current);
+
+    regions.retain(|r| r.end - r.start >= MIN_SUSTAIN_SECS);
+    regions
+}
+
+/// The clip around a region, in seconds since the start of the stream.
+fn clip_bounds(region: &Region, length: usize) -> (usize, usize) {
+    let mut start = region.start.saturating_sub(PRE_ROLL_SECS);
+    let mut end = (region.end + POST_ROLL_SECS).min(length);
+
+    if end - start > MAX_CLIP_SECS {
+        // Keep the peak, with more of what led up to it than of what came after.
+        start = region.peak.saturating_sub(MAX_CLIP_SECS * 2 / 3).max(start);
+        end = (start + MAX_CLIP_SECS).min(end);
+    } else if end - start < MIN_CLIP_SECS {
+        let missing = MIN_CLIP_SECS - (end - start);
+        start = start.saturating_sub(missing / 2);
+        end = (start + MIN_CLIP_SECS).min(length);
+    }
+
+    (start, end)
+}
+
+/// Whatever chat was repeating during the highlight: the most repeated message if there is one,
+/// the most used word otherwise.
+async fn get_title(stream: &StreamInfo, start: usize, end: usize) -> Result<Option<String>> {
+    if !stream.has_chat {
+        return Ok(None);
+    }
+
+    let items = FileReader::new(stream.clone())
+        .await?
+        .get_between(
+            stream.timestamp + chrono::Duration::seconds(start as i64),
+            stream.timestamp + chrono::Duration::seconds(end as i64),
+        )
+        .await?;
+
+    // Counts with the first spelling that was seen.
+    let mut messages: HashMap<String, (usize, String)> = HashMap::new();
+    let mut words: HashMap<String, (usize, String)> = HashMap::new();
+    for item in items {
+        let content: Value = serde_json::from_str(item.content.get())?;
+        if content["type"] != "chat" {
+            continue;
+        }
+        let text = match content["message"].as_str() {
+            None => continue,
+            Some(text) => text.split_whitespace().collect::<Vec<_>>().join(" "),
+        };
+        if text.is_empty() {
+            continue;
+        }
+
+        messages
+            .entry(text.to_lowercase())
+            .or_insert_with(|| (0, text.clone()))
+            .0 += 1;
+
+        // Spamming a word in a single message counts once.
+        let mut seen = vec![];
+        for word in text.split(' ') {
+            let key = word.to_lowercase();
+            if seen.contains(&key) {
+                continue;
+            }
+            words
+                .entry(key.clone())
+                .or_insert_with(|| (0, word.to_owned()))
+                .0 += 1;
+            seen.push(key);
+        }
+    }
+
+    let most_repeated = |counts: HashMap<String, (usize, String)>| {
+        counts
+            .into_values()
+            .filter(|(count, _)| *count >= MIN_TITLE_REPEATS)
+            .max_by_key(|(count, _)| *count)
+            .map(|(_, text)| text.chars().take(MAX_TITLE_LENGTH).collect::<String>())
+    };
+    Ok(most_repeated(messages).or_else(|| most_repeated(words)))
+}
+
+/// Find the highlights of a stream from its hype.
+pub async fn get_highlights(
+    stream: &StreamInfo,
+    datapoints: &[HypeDatapoint],
+) -> Result<Vec<Highlight>> {
+    let length = stream.duration.as_secs() as usize;
+    if length == 0 {
+        return Ok(vec![]);
+    }
+
+    let mut hype = vec![0.0; length];
+    for dp in datapoints {
+        let second = (dp.ts - stream.timestamp).num_seconds();
+        if (0..length as i64).contains(&second) {
+            hype[second as usize] = dp.hype;
+        }
+    }
+
+    let mut regions = find_regions(&hype);
+    regions.sort_by(|a, b| b.peak_z.total_cmp(&a.peak_z));
+    regions.truncate(MAX_HIGHLIGHTS);
+
+    let mut highlights = vec![];
+    for region in regions {
+        let (start, end) = clip_bounds(&region, length);
+        let title = match get_title(stream, start, end).await {
+            Ok(title) => title,
+            Err(e) => {
+                eprintln!("[{}] failed to get title from chat: {:?}", stream.id, e);
+                None
+            }
+        };
+
+        highlights.push(Highlight {
+            start_time: Duration::from_secs(start as u64),
+            duration: Duration::from_secs((end - start) as u64),
+            // Hype at twice the threshold is a coin toss, a bit more than that is almost certain.
+            confidence: 1.0 / (1.0 + (-2.0 * (region.peak_z - 2.0 * Z_THRESHOLD)).exp()),
+            title,
+        });
+    }
+
+    Ok(highlights)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(start: usize, end: usize, peak: usize) -> Region {
+        Region {
+            start,
+            end,
+            peak,
+            peak_z: 3.0,
+        }
+    }
+
+    #[test]
+    fn clip_bounds_adds_the_rolls() {
+        assert_eq!(clip_bounds(&region(100, 120, 110), 1000), (85, 125));
+    }
+
+    #[test]
+    fn clip_bounds_clamps_long_clips_around_the_peak() {
+        assert_eq!(clip_bounds(&region(100, 300, 250), 1000), (210, 270));
+        assert_eq!(clip_bounds(&region(100, 300, 105), 1000), (85, 145));
+    }
+
+    #[test]
+    fn clip_bounds_extends_short_clips() {
+        assert_eq!(clip_bounds(&region(0, 5, 2), 1000), (0, 20));
+        assert_eq!(clip_bounds(&region(2, 7, 4), 12), (0, 12));
+    }
+
+    #[test]
+    fn clip_bounds_stay_in_the_stream_and_in_range() {
+        for length in [12, 30, 100, 500] {
+            for start in 0..length {
+                for end in (start + MIN_SUSTAIN_SECS..=length).step_by(7) {
+                    for peak in (start..end).step_by(3) {
+                        let (clip_start, clip_end) = clip_bounds(&region(start, end, peak), length);
+                        assert!(clip_start <= peak && peak < clip_end);
+                        assert!(clip_end <= length);
+                        assert!(clip_end - clip_start <= MAX_CLIP_SECS);
+                        assert!(clip_end - clip_start >= MIN_CLIP_SECS.min(length));
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,417 @@
+use streamwatch_shared::serde::from_str_option;
+use streamwatch_shared::types::{HypeColumns, HypeDatapoint};
+
+use std::collections::HashMap;
+
+use tokio::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+
+use once_cell::sync::Lazy;
+
+use anyhow::{bail, Result};
+
+/// How loudness and chat are brought to a common scale before they are weighted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Normalization {
+    /// The same scale for every stream. Loudness (momentary, in LUFS) goes through
+    /// `(1 + tanh(5 * (x + 75/2) / 80)) / 2`, which is flatter at both ends than the linear
+    /// `(x + 75) / 80`, and chat is the number of messages per second divided by 5.
+    Fixed,
+    /// Standard deviations from the average of the stream, so quiet streams get peaks too.
+    ZScore,
+    /// The fraction of the seconds of the stream that are lower, from 0 to 1.
+    Percentile,
+}
+
+/// How the hype of every second of a stream is computed from its loudness and chat speed.
+///
+/// With per-stream normalisation every stream averages out to about the same hype, so
+/// `hype_average` only tells streams apart with [`Normalization::Fixed`].
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HypeModel {
+    pub loudness_weight: f64,
+    pub chat_weight: f64,
+    pub normalization: Normalization,
+    /// Seconds the loudness is averaged over before it is normalised, 1 to not smooth it.
+    pub loudness_window: usize,
+    /// Seconds the chat speed is averaged over before it is normalised, 1 to not smooth it.
+    pub chat_window: usize,
+}
+
+impl Default for HypeModel {
+    fn default() -> Self {
+        Self {
+            loudness_weight: 0.25,
+            chat_weight: 1.0,
+            normalization: Normalization::Fixed,
+            loudness_window: 1,
+            chat_window: 1,
+        }
+    }
+}
+
+/// Changes to the configured [`HypeModel`], to try out other weightings.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HypeModelOverride {
+    #[serde(deserialize_with = "from_str_option::deserialize")]
+    pub loudness_weight: Option<f64>,
+    #[serde(deserialize_with = "from_str_option::deserialize")]
+    pub chat_weight: Option<f64>,
+    pub normalization: Option<Normalization>,
+    #[serde(deserialize_with = "from_str_option::deserialize")]
+    pub loudness_window: Option<usize>,
+    #[serde(deserialize_with = "from_str_option::deserialize")]
+    pub chat_window: Option<usize>,
+}
+
+impl HypeModelOverride {
+    pub fn apply(self, model: &HypeModel) -> HypeModel {
+        HypeModel {
+            loudness_weight: self.loudness_weight.unwrap_or(model.loudness_weight),
+            chat_weight: self.chat_weight.unwrap_or(model.chat_weight),
+            normalization: self.normalization.unwrap_or(model.normalization),
+            loudness_window: self.loudness_window.unwrap_or(model.loudness_window),
+            chat_window: self.chat_window.unwrap_or(model.chat_window),
+        }
+    }
+}
+
+/// Centred moving average over `window` values, skipping the missing ones.
+fn smooth(values: &[Option<f64>], window: usize) -> Vec<Option<f64>> {
+    if window <= 1 {
+        return values.to_vec();
+    }
+
+    (0..values.len())
+        .map(|i| {
+            let start = i.saturating_sub(window / 2);
+            let end = (start + window).min(values.len());
+            let present: Vec<f64> = values[start..end].iter().flatten().copied().collect();
+            (!present.is_empty()).then(|| present.iter().sum::<f64>() / present.len() as f64)
+        })
+        .collect()
+}
+
+fn fixed_loudness(lufs: f64) -> f64 {
+    (1.0 + (5.0 * (lufs + 75.0 / 2.0) / 80.0).tanh()) / 2.0
+}
+
+fn fixed_chat(messages: f64) -> f64 {
+    messages / 5.0
+}
+
+impl HypeModel {
+    pub fn validate(&self) -> Result<()> {
+        if !self.loudness_weight.is_finite() || !self.chat_weight.is_finite() {
+            bail!("hype weights have to be finite numbers");
+        }
+        if self.loudness_window == 0 || self.chat_window == 0 {
+            bail!("hype smoothing windows have to be at least 1 second");
+        }
+        Ok(())
+    }
+
+    fn normalize(&self, values: Vec<Option<f64>>, fixed: fn(f64) -> f64) -> Vec<Option<f64>> {
+        let mut present: Vec<f64> = values.iter().flatten().copied().collect();
+        let n = present.len() as f64;
+
+        match self.normalization {
+            Normalization::Fixed => values.into_iter().map(|v| v.map(fixed)).collect(),
+            Normalization::ZScore => {
+                let mean = present.iter().sum::<f64>() / n;
+                let sd = (present.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n).sqrt();
+                values
+                    .into_iter()
+                    .map(|v| v.map(|v| if sd > 0.0 { (v - mean) / sd } else { 0.0 }))
+                    .collect()
+            }
+            Normalization::Percentile => {
+                present.sort_by(f64::total_cmp);
+                values
+                    .into_iter()
+                    .map(|v| {
+                        v.map(|v| {
+                            // Ties count half, so a stream that is all the same is at 0.5.
+                            let lower = present.partition_point(|p| *p < v);
+                            let upper = present.partition_point(|p| *p <= v);
+                            (lower + upper) as f64 / (2.0 * n)
+                        })
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// Fill in the hype of the datapoints of a stream, sorting them by time. Seconds without
+    /// loudness or chat count as 0 for that part.
+    pub fn apply(&self, datapoints: &mut [HypeDatapoint]) {
+        datapoints.sort_by_key(|dp| dp.ts);
+
+        let loudness: Vec<_> = datapoints.iter().map(|dp| dp.loudness).collect();
+        let loudness = self.normalize(smooth(&loudness, self.loudness_window), fixed_loudness);
+        let chat: Vec<_> = datapoints
+            .iter()
+            .map(|dp| dp.chat_hype.map(f64::from))
+            .collect();
+        let chat = self.normalize(smooth(&chat, self.chat_window), fixed_chat);
+
+        for ((dp, loudness), chat) in datapoints.iter_mut().zip(loudness).zip(chat) {
+            dp.hype = self.loudness_weight * loudness.unwrap_or(0.0)
+                + self.chat_weight * chat.unwrap_or(0.0);
+        }
+    }
+}
+
+/// The average hype of a stream, `None` if there is no hype data.
+pub fn hype_average(datapoints: &[HypeDatapoint]) -> Option<f64> {
+    (!datapoints.is_empty())
+        .then(|| datapoints.iter().map(|dp| dp.hype).sum::<f64>() / datapoints.len() as f64)
+}
+
+/// How the datapoints in a bucket are combined when downsampling.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Downsampling {
+    #[default]
+    Mean,
+    Max,
+    /// Largest-Triangle-Three-Buckets: of every bucket, the datapoint that keeps the shape of
+    /// the hype graph best.
+    Lttb,
+}
+
+/// How far hype is downsampled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Resolution {
+    /// About this many buckets over the whole stream.
+    Buckets(usize),
+    /// Buckets of this many seconds.
+    Seconds(i64),
+}
+
+impl Resolution {
+    fn bucket_secs(&self, span: i64) -> i64 {
+        match *self {
+            Resolution::Buckets(n) => (span + n as i64 - 1) / n as i64,
+            Resolution::Seconds(secs) => secs,
+        }
+        .max(1)
+    }
+}
+
+fn mean(values: &mut dyn Iterator<Item = f64>) -> Option<f64> {
+    let (sum, n) = values.fold((0.0, 0), |(sum, n), v| (sum + v, n + 1));
+    (n > 0).then(|| sum / n as f64)
+}
+
+fn max(values: &mut dyn Iterator<Item = f64>) -> Option<f64> {
+    values.reduce(f64::max)
+}
+
+/// The indices of the `threshold` points that keep the shape of the graph best, see
+/// <https://skemman.is/bitstream/1946/15343/3/SS_MSc_thesis.pdf>.
+fn lttb(points: &[(f64, f64)], threshold: usize) -> Vec<usize> {
+    let n = points.len();
+    let threshold = threshold.max(3);
+    if threshold >= n {
+        return (0..n).collect();
+    }
+
+    // The first and last points are always kept, the rest is split into buckets.
+    let every = (n - 2) as f64 / (threshold - 2) as f64;
+    let mut selected = Vec::with_capacity(threshold);
+    selected.push(0);
+    let mut a = 0;
+    for i in 0..threshold - 2 {
+        // The point in this bucket that forms the largest triangle with the previously
+        // selected point and the average of the next bucket.
+        let next_start = ((i + 1) as f64 * every) as usize + 1;
+        let next_end = (((i + 2) as f64 * every) as usize + 1).min(n);
+        let next = &points[next_start..next_end];
+        let avg_x = next.iter().map(|p| p.0).sum::<f64>() / next.len() as f64;
+        let avg_y = next.iter().map(|p| p.1).sum::<f64>() / next.len() as f64;
+
+        let (ax, ay) = points[a];
+        let start = (i as f64 * every) as usize + 1;
+        let end = next_start;
+        a = (start..end)
+            .max_by(|&j, &k| {
+                let area =
+                    |(x, y): (f64, f64)| ((ax - avg_x) * (y - ay) - (ax - x) * (avg_y - ay)).abs();
+                area(points[j]).total_cmp(&area(points[k]))
+            })
+            .unwrap_or(start);
+        selected.push(a);
+    }
+    selected.push(n - 1);
+
+    selected
+}
+
+/// Downsample the datapoints of a stream, which have to be sorted by time.
+pub fn downsample(
+    datapoints: &[HypeDatapoint],
+    resolution: Resolution,
+    method: Downsampling,
+) -> HypeColumns {
+    let (first, last) = match (datapoints.first(), datapoints.last()) {
+        (Some(first), Some(last)) => (first.ts.timestamp(), last.ts.timestamp()),
+        _ => return HypeColumns::default(),
+    };
+    let bucket_secs = resolution.bucket_secs(last - first + 1);
+
+    let mut columns = HypeColumns::default();
+    let combine: fn(&mut dyn Iterator<Item = f64>) -> Option<f64> = match method {
+        Downsampling::Mean => mean,
+        Downsampling::Max => max,
+        Downsampling::Lttb => {
+            let points: Vec<_> = datapoints
+                .iter()
+                .map(|dp| (dp.ts.timestamp() as f64, dp.hype))
+                .collect();
+            let threshold = ((last - first + 1 + bucket_secs - 1) / bucket_secs) as usize;
+            for i in lttb(&points, threshold) {
+                let dp = &datapoints[i];
+                columns.ts.push(dp.ts.timestamp());
+                columns.loudness.push(dp.loudness);
+                columns.chat_hype.push(dp.chat_hype.map(f64::from));
+                columns.hype.push(dp.hype);
+            }
+            return columns;
+        }
+    };
+
+    let bucket = |dp: &HypeDatapoint| (dp.ts.timestamp() - first) / bucket_secs;
+    for chunk in datapoints.chunk_by(|a, b| bucket(a) == bucket(b)) {
+        columns.ts.push(first + bucket(&chunk[0]) * bucket_secs);
+        columns
+            .loudness
+            .push(combine(&mut chunk.iter().filter_map(|dp| dp.loudness)));
+        columns.chat_hype.push(combine(
+            &mut chunk.iter().filter_map(|dp| dp.chat_hype.map(f64::from)),
+        ));
+        columns
+            .hype
+            .push(combine(&mut chunk.iter().map(|dp| dp.hype)).unwrap_or(0.0));
+    }
+
+    columns
+}
+
+/// Cached downsampled hype is cleared when it reaches this many entries.
+const MAX_CACHED: usize = 1024;
+
+type DownsampledKey = (i64, Resolution, Downsampling);
+
+/// Downsampled hype of the configured model as JSON, by stream, resolution and method.
+static DOWNSAMPLED_CACHE: Lazy<Mutex<HashMap<DownsampledKey, Box<RawValue>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub async fn get_cached_downsampled(
+    stream_id: i64,
+    resolution: Resolution,
+    method: Downsampling,
+) -> Option<Box<RawValue>> {
+    let cache = DOWNSAMPLED_CACHE.lock().await;
+    cache.get(&(stream_id, resolution, method)).cloned()
+}
+
+pub async fn cache_downsampled(
+    stream_id: i64,
+    resolution: Resolution,
+    method: Downsampling,
+    json: Box<RawValue>,
+) {
+    let mut cache = DOWNSAMPLED_CACHE.lock().await;
+    if cache.len() >= MAX_CACHED {
+        cache.clear();
+    }
+    cache.insert((stream_id, resolution, method), json);
+}
+
+/// Forget the downsampled hype of a stream, when its loudness or chatspeed changed.
+pub async fn forget_cached_downsampled(stream_id: i64) {
+    let mut cache = DOWNSAMPLED_CACHE.lock().await;
+    cache.retain(|(id, _, _), _| *id != stream_id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::timestamp;
+
+    fn datapoint(ts: i64, loudness: Option<f64>, chat_hype: Option<i32>) -> HypeDatapoint {
+        HypeDatapoint {
+            ts: timestamp(ts),
+            loudness,
+            chat_hype,
+            hype: 0.0,
+        }
+    }
+
+    #[test]
+    fn default_model_is_the_old_formula() {
+        let mut datapoints = vec![
+            datapoint(0, Some(-75.0), Some(0)),
+            datapoint(1, Some(-37.5), None),
+            datapoint(2, None, Some(7)),
+            datapoint(3, Some(-10.0), Some(3)),
+            datapoint(4, None, None),
+        ];
+        HypeModel::default().apply(&mut datapoints);
+
+        for dp in datapoints {
+            let old = dp
+                .loudness
+                .map(|m| 1.0 / 8.0 * (1.0 + (5.0 * (m + 75.0 / 2.0) / 80.0).tanh()))
+                .unwrap_or(0.0)
+                + dp.chat_hype.map(|m| (m as f64) / 5.0).unwrap_or(0.0);
+            assert!((dp.hype - old).abs() < 1e-12, "{} != {}", dp.hype, old);
+        }
+    }
+
+    #[test]
+    fn lttb_keeps_endpoints_and_one_point_per_bucket() {
+        let points: Vec<(f64, f64)> = (0..1000)
+            .map(|i| (i as f64, (i as f64 / 17.0).sin() * (i % 7) as f64))
+            .collect();
+
+        for threshold in [3, 4, 10, 99, 500, 999] {
+            let selected = lttb(&points, threshold);
+            assert_eq!(selected.len(), threshold);
+            assert_eq!(selected.first(), Some(&0));
+            assert_eq!(selected.last(), Some(&(points.len() - 1)));
+
+            let every = (points.len() - 2) as f64 / (threshold - 2) as f64;
+            for (i, &index) in selected[1..threshold - 1].iter().enumerate() {
+                let start = (i as f64 * every) as usize + 1;
+                let end = ((i + 1) as f64 * every) as usize + 1;
+                assert!(
+                    (start..end).contains(&index),
+                    "{} not in {}..{}",
+                    index,
+                    start,
+                    end
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn lttb_keeps_small_inputs_and_peaks() {
+        let points = [(0.0, 1.0), (1.0, 2.0), (2.0, 3.0)];
+        assert_eq!(lttb(&points, 3), vec![0, 1, 2]);
+        assert_eq!(lttb(&points, 10), vec![0, 1, 2]);
+        assert_eq!(lttb(&points[..1], 1), vec![0]);
+        assert_eq!(lttb(&[], 5), Vec::<usize>::new());
+
+        let mut points: Vec<(f64, f64)> = (0..100).map(|i| (i as f64, 0.0)).collect();
+        points[42].1 = 10.0;
+        assert!(lttb(&points, 10).contains(&42));
+    }
+}
@@ -1,24 +1,41 @@
 use std::borrow::BorrowMut;
+use std::collections::{HashMap, HashSet};
+use std::io::ErrorKind;
+use std::ops::DerefMut;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use std::time::Instant;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use crate::chat::build_seek_index;
+use crate::chat_index::get_chat_lines;
+use crate::chat_overlay::ChatOverlay;
 use crate::chatspeed::get_chatspeed_points;
 use crate::create_preview::{
-    create_clip_preview, create_clip_thumbnail, create_preview, create_thumbnails,
-    get_sections_from_file, PREVIEW_PER_SECS, SCRUB_PER_SECS,
+    create_clip_export, create_clip_preview, create_clip_thumbnail, create_hls, create_preview,
+    create_scrub_sprites, create_thumbnails, get_preview_sections_from_file,
+    get_sections_from_file, pick_thumbnail_times, HlsRendition, HLS_LADDER, PREVIEW_PER_SECS,
+    SCRUB_PER_SECS,
 };
 use crate::db::Database;
+use crate::highlights::get_highlights;
+use crate::hype::hype_average;
 use crate::loudness::get_loudness_points;
-use crate::util::get_conn;
-use crate::{okky, update_cache, DB, STREAMS_DIR};
+use crate::util::{get_conn, timestamp};
+use crate::{okky, update_cache, CONFIG, DB};
 
-use sqlx::SqliteConnection;
-use streamwatch_shared::types::{Clip, StreamInfo, StreamJson};
+use sqlx::sqlite::SqliteRow;
+use sqlx::{Row, SqliteConnection};
+use streamwatch_shared::functions::{get_video_height, has_audio, CommandError};
+use streamwatch_shared::types::{Clip, JobInfo, JobsOverview, StreamInfo, StreamJson};
 
-use tokio::sync::{self, mpsc};
+use tokio::fs::remove_dir_all;
+use tokio::sync::Notify;
 
-use once_cell::sync::OnceCell;
+use chrono::Utc;
+
+use serde::{Deserialize, Serialize};
+
+use once_cell::sync::{Lazy, OnceCell};
 
 use anyhow::{anyhow, Result};
 
@@ -30,8 +47,7 @@ async fn expect_stream(conn: &mut SqliteConnection, stream_id: i64) -> Result<St
 }
 
 async fn expect_clip(conn: &mut SqliteConnection, clip_id: i64) -> Result<Clip> {
-    let clips = Database::get_clips(conn, None).await?;
-    match clips.into_iter().find(|c| c.id == clip_id) {
+    match Database::get_clip_by_id(conn, clip_id).await? {
         None => Err(anyhow!("clip {} not found", clip_id)),
         Some(s) => Ok(s),
     }
@@ -49,59 +65,205 @@ async fn expect_clip_stream(clip_id: i64) -> Result<(Clip, StreamJson)> {
 
 pub static SENDER: OnceCell<JobSender> = OnceCell::new();
 
+/// How many failed jobs are returned by [`get_jobs_overview`].
+const RECENT_FAILURES: i64 = 50;
+
+/// How often idle workers look for new jobs even if they weren't notified, in case a job was added
+/// to the table some other way.
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Finished jobs are kept around this long before they are removed on startup.
+const KEEP_DONE_JOBS: chrono::Duration = chrono::Duration::days(7);
+
+/// Jobs that are currently being executed by a worker, notified when the job should be stopped.
+static RUNNING_JOBS: Lazy<Mutex<HashMap<i64, Arc<Notify>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Stop the given jobs if they are running in this process. Dropping the job kills any ffmpeg
+/// processes it started.
+fn stop_running_jobs(job_ids: &[i64]) {
+    let running = RUNNING_JOBS.lock().unwrap();
+    for id in job_ids {
+        if let Some(cancel) = running.get(id) {
+            cancel.notify_one();
+        }
+    }
+}
+
+/// Adds jobs to the `jobs` table and wakes up a worker to handle them.
 #[derive(Debug)]
 pub struct JobSender {
-    thumbnail_jobs: mpsc::UnboundedSender<Job>,
-    preview_jobs: mpsc::UnboundedSender<Job>,
-    clip_thumbnail_jobs: mpsc::UnboundedSender<Job>,
-    clip_preview_jobs: mpsc::UnboundedSender<Job>,
-    loudness_jobs: mpsc::UnboundedSender<Job>,
-    chatspeed_jobs: mpsc::UnboundedSender<Job>,
+    notify: Arc<Notify>,
 }
 impl JobSender {
-    pub fn send(&self, job: Job) -> Result<(), mpsc::error::SendError<Job>> {
-        match job {
-            j @ Job::Thumbnails { .. } => self.thumbnail_jobs.send(j),
-            j @ Job::Preview { .. } => self.preview_jobs.send(j),
-            j @ Job::ClipThumbnail { .. } => self.clip_thumbnail_jobs.send(j),
-            j @ Job::ClipPreview { .. } => self.clip_preview_jobs.send(j),
-            j @ Job::Loudness { .. } => self.loudness_jobs.send(j),
-            j @ Job::Chatspeed { .. } => self.chatspeed_jobs.send(j),
+    /// Queue a job. Jobs of a kind that is disabled for their stream are skipped.
+    ///
+    /// A job supersedes the jobs of the same kind for the same stream or clip that are still
+    /// queued or running, since those would work on an outdated version of it. They are cancelled,
+    /// and the running ones are stopped.
+    pub async fn send(&self, job: Job) -> Result<()> {
+        if let Some(reason) = disabled_reason(&job).await? {
+            println!(
+                "[{}] {} is disabled for this stream ({}), skipping",
+                job.stream_id().unwrap(),
+                job.kind(),
+                reason
+            );
+            return Ok(());
+        }
+
+        let payload = serde_json::to_string(&job)?;
+        let now = Utc::now().timestamp();
+
+        let mut tx = DB.get().unwrap().pool.begin().await?;
+
+        let kind = job.kind();
+        let stream_id = job.stream_id();
+        let clip_id = job.clip_id();
+        let priority = job.priority();
+        let job_id = sqlx::query!(
+            r#"
+            INSERT INTO jobs
+                (kind, stream_id, clip_id, payload, status, priority, created_at)
+            VALUES
+                (?1, ?2, ?3, ?4, 'queued', ?5, ?6)
+            "#,
+            kind,
+            stream_id,
+            clip_id,
+            payload,
+            priority,
+            now,
+        )
+        .execute(tx.deref_mut())
+        .await?
+        .last_insert_rowid();
+
+        // Clip jobs have no stream, they are matched on their clip instead.
+        let target = match (stream_id, clip_id) {
+            (Some(stream_id), _) => Some(("stream_id", stream_id)),
+            (None, Some(clip_id)) => Some(("clip_id", clip_id)),
+            (None, None) => None,
+        };
+
+        let mut superseded_running = vec![];
+        if let Some((column, id)) = target {
+            superseded_running = sqlx::query(&format!(
+                "SELECT id FROM jobs WHERE kind = ?1 AND {} = ?2 AND status = 'running'",
+                column
+            ))
+            .bind(job.kind())
+            .bind(id)
+            .map(|row: SqliteRow| row.get("id"))
+            .fetch_all(tx.deref_mut())
+            .await?;
+
+            sqlx::query(&format!(
+                r#"
+                UPDATE jobs
+                SET
+                    status = 'cancelled',
+                    last_error = ?1,
+                    finished_at = ?2
+                WHERE kind = ?3
+                    AND {} = ?4
+                    AND status IN ('queued', 'running')
+                    AND id != ?5
+                "#,
+                column
+            ))
+            .bind(format!("superseded by job {}", job_id))
+            .bind(now)
+            .bind(job.kind())
+            .bind(id)
+            .bind(job_id)
+            .execute(tx.deref_mut())
+            .await?;
         }
+
+        tx.commit().await?;
+
+        stop_running_jobs(&superseded_running);
+        self.notify.notify_one();
+        Ok(())
     }
-}
 
-pub struct JobReceiver {
-    thumbnail_jobs: mpsc::UnboundedReceiver<Job>,
-    preview_jobs: mpsc::UnboundedReceiver<Job>,
-    clip_thumbnail_jobs: mpsc::UnboundedReceiver<Job>,
-    clip_preview_jobs: mpsc::UnboundedReceiver<Job>,
-    loudness_jobs: mpsc::UnboundedReceiver<Job>,
-    chatspeed_jobs: mpsc::UnboundedReceiver<Job>,
-}
-impl JobReceiver {
-    pub async fn recv(&mut self) -> Option<Job> {
-        let thumbnails = self.thumbnail_jobs.recv();
-        let previews = self.preview_jobs.recv();
-        let clip_thumbnails = self.clip_thumbnail_jobs.recv();
-        let clip_previews = self.clip_preview_jobs.recv();
-        let chatspeed = self.chatspeed_jobs.recv();
-        let loudness = self.loudness_jobs.recv();
-
-        tokio::select! {
-            biased;
-            Some(job) = thumbnails => Some(job),
-            Some(job) = previews => Some(job),
-            Some(job) = clip_thumbnails => Some(job),
-            Some(job) = clip_previews => Some(job),
-            Some(job) = chatspeed => Some(job),
-            Some(job) = loudness => Some(job),
-            else => None,
+    /// Queue a failed or cancelled job again. Returns false if there is no such job, or if it is
+    /// queued, running or done.
+    pub async fn retry(&self, job_id: i64) -> Result<bool> {
+        let res = sqlx::query!(
+            r#"
+            UPDATE jobs
+            SET
+                status = 'queued',
+                started_at = NULL,
+                finished_at = NULL,
+                run_after = NULL
+            WHERE id = ?1
+                AND status IN ('failed', 'cancelled')
+            "#,
+            job_id,
+        )
+        .execute(&DB.get().unwrap().pool)
+        .await?;
+
+        if res.rows_affected() == 0 {
+            return Ok(false);
         }
+        self.notify.notify_one();
+        Ok(true)
+    }
+
+    /// Cancel a queued or running job, stopping it if it is running. Returns false if there is
+    /// no such job or it already finished.
+    pub async fn cancel(&self, job_id: i64) -> Result<bool> {
+        let now = Utc::now().timestamp();
+        let res = sqlx::query!(
+            "UPDATE jobs SET status = 'cancelled', finished_at = ?1 WHERE id = ?2 AND status IN ('queued', 'running')",
+            now,
+            job_id,
+        )
+        .execute(&DB.get().unwrap().pool)
+        .await?;
+
+        if res.rows_affected() == 0 {
+            return Ok(false);
+        }
+        stop_running_jobs(&[job_id]);
+        Ok(true)
+    }
+
+    /// Ids of the streams that have jobs that are queued or running.
+    pub async fn pending_stream_ids(&self) -> Result<HashSet<i64>> {
+        let ids = sqlx::query!(
+            r#"SELECT DISTINCT stream_id AS "stream_id!" FROM jobs WHERE status IN ('queued', 'running') AND stream_id IS NOT NULL"#,
+        )
+        .map(|row| row.stream_id)
+        .fetch_all(&DB.get().unwrap().pool)
+        .await?;
+        Ok(ids.into_iter().collect())
+    }
+
+    /// Whether the same job is already queued or running.
+    pub async fn is_pending(&self, job: &Job) -> Result<bool> {
+        let kind = job.kind();
+        let payload = serde_json::to_string(job)?;
+        let count = sqlx::query!(
+            "SELECT COUNT(*) AS count FROM jobs WHERE kind = ?1 AND payload = ?2 AND status IN ('queued', 'running')",
+            kind,
+            payload,
+        )
+        .map(|row| row.count)
+        .fetch_one(&DB.get().unwrap().pool)
+        .await?;
+        Ok(count > 0)
     }
 }
 
-#[derive(Clone, Debug)]
+// One line per job, rustfmt would spread all of them out because of `ClipExport`.
+#[rustfmt::skip]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
 pub enum Job {
     Preview { stream_id: i64, path: PathBuf },
     Thumbnails { stream_id: i64, path: PathBuf },
@@ -109,15 +271,125 @@ pub enum Job {
     ClipThumbnail { clip_id: i64 },
     Loudness { stream_id: i64 },
     Chatspeed { stream_id: i64 },
+    Hls { stream_id: i64, path: PathBuf },
+    ClipExport { clip_id: i64, burn_title: bool, burn_chat: bool },
+    Highlights { stream_id: i64 },
+    ChatIndex { stream_id: i64 },
+    ChatSeekIndex { stream_id: i64 },
+    /// Only the scrub sprite sheets, for streams whose thumbnails were made before there were any.
+    ScrubSprites { stream_id: i64, path: PathBuf },
+}
+
+impl Job {
+    pub const KINDS: [&'static str; 12] = [
+        "preview",
+        "thumbnails",
+        "clip_preview",
+        "clip_thumbnail",
+        "loudness",
+        "chatspeed",
+        "hls",
+        "clip_export",
+        "highlights",
+        "chat_index",
+        "chat_seek_index",
+        "scrub_sprites",
+    ];
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Job::Preview { .. } => "preview",
+            Job::Thumbnails { .. } => "thumbnails",
+            Job::ClipPreview { .. } => "clip_preview",
+            Job::ClipThumbnail { .. } => "clip_thumbnail",
+            Job::Loudness { .. } => "loudness",
+            Job::Chatspeed { .. } => "chatspeed",
+            Job::Hls { .. } => "hls",
+            Job::ClipExport { .. } => "clip_export",
+            Job::Highlights { .. } => "highlights",
+            Job::ChatIndex { .. } => "chat_index",
+            Job::ChatSeekIndex { .. } => "chat_seek_index",
+            Job::ScrubSprites { .. } => "scrub_sprites",
+        }
+    }
+
+    pub fn stream_id(&self) -> Option<i64> {
+        match self {
+            Job::Preview { stream_id, .. }
+            | Job::Thumbnails { stream_id, .. }
+            | Job::Loudness { stream_id }
+            | Job::Chatspeed { stream_id }
+            | Job::Highlights { stream_id }
+            | Job::ChatIndex { stream_id }
+            | Job::ChatSeekIndex { stream_id }
+            | Job::ScrubSprites { stream_id, .. }
+            | Job::Hls { stream_id, .. } => Some(*stream_id),
+            Job::ClipPreview { .. } | Job::ClipThumbnail { .. } | Job::ClipExport { .. } => None,
+        }
+    }
+
+    pub fn clip_id(&self) -> Option<i64> {
+        match self {
+            Job::ClipPreview { clip_id }
+            | Job::ClipThumbnail { clip_id }
+            | Job::ClipExport { clip_id, .. } => Some(*clip_id),
+            _ => None,
+        }
+    }
+
+    /// Jobs with a lower priority are picked up first.
+    pub fn priority(&self) -> i64 {
+        match self {
+            Job::Thumbnails { .. } => 0,
+            Job::Preview { .. } => 1,
+            Job::ClipThumbnail { .. } => 2,
+            Job::ClipPreview { .. } => 3,
+            // someone is waiting for the download
+            Job::ClipExport { .. } => 4,
+            Job::Chatspeed { .. } => 5,
+            Job::Loudness { .. } => 6,
+            Job::Hls { .. } => 7,
+            Job::Highlights { .. } => 8,
+            Job::ChatIndex { .. } => 9,
+            Job::ChatSeekIndex { .. } => 10,
+            Job::ScrubSprites { .. } => 11,
+        }
+    }
+}
+
+/// The hype of a stream as `(second, hype)` pairs, empty if there is none (yet).
+async fn get_stream_hype(stream_id: i64) -> Result<Vec<(i32, f64)>> {
+    let mut conn = get_conn().await?;
+    let stream = expect_stream(conn.borrow_mut(), stream_id).await?;
+    let model = &CONFIG.get().unwrap().hype;
+    let datapoints = match Database::get_hype_datapoints(conn.borrow_mut(), stream_id, model).await
+    {
+        Ok(datapoints) => datapoints,
+        Err(e) => {
+            eprintln!("[{}] failed to get hype datapoints: {:?}", stream_id, e);
+            return Ok(vec![]);
+        }
+    };
+
+    Ok(datapoints
+        .into_iter()
+        .map(|dp| {
+            (
+                (dp.ts - stream.info.timestamp).num_seconds() as i32,
+                dp.hype,
+            )
+        })
+        .collect())
 }
 
 async fn make_preview(stream_id: i64, path: PathBuf) -> Result<()> {
-    let sections = get_sections_from_file(&path, PREVIEW_PER_SECS).await?;
+    let hype = get_stream_hype(stream_id).await?;
+    let sections = get_preview_sections_from_file(&path, PREVIEW_PER_SECS, &hype).await?;
     println!("[{}] sections are: {:?}", stream_id, sections);
 
     let start = Instant::now();
 
-    let preview_path = StreamInfo::preview_path(stream_id);
+    let preview_path = StreamInfo::preview_path(&CONFIG.get().unwrap().previews_dir, stream_id);
     create_preview(&path, &preview_path, &sections).await?;
 
     let db = DB.get().unwrap();
@@ -139,9 +411,13 @@ async fn make_clip_preview(clip_id: i64) -> Result<()> {
 
     let start = Instant::now();
 
-    let preview_path = Clip::preview_path(clip_id);
+    let config = CONFIG.get().unwrap();
+    let preview_path = Clip::preview_path(&config.previews_dir, clip_id);
     create_clip_preview(
-        &stream.info.file_name.stream_path(STREAMS_DIR),
+        &stream
+            .info
+            .file_name
+            .stream_path(config.library_dir(&stream.info.library)?),
         &preview_path,
         clip.start_time,
         clip.duration,
@@ -163,18 +439,76 @@ async fn make_clip_preview(clip_id: i64) -> Result<()> {
     Ok(())
 }
 
+async fn make_clip_export(clip_id: i64, burn_title: bool, burn_chat: bool) -> Result<()> {
+    let (clip, stream) = expect_clip_stream(clip_id).await?;
+
+    let start = Instant::now();
+
+    let config = CONFIG.get().unwrap();
+    let path = stream
+        .info
+        .file_name
+        .stream_path(config.library_dir(&stream.info.library)?);
+    let height = get_video_height(&path).await?;
+
+    let title = clip.title.as_deref().filter(|_| burn_title);
+    let chat = if burn_chat && stream.info.has_chat {
+        Some(ChatOverlay::new(stream.info, clip.start_time, clip.duration, height).await?)
+    } else {
+        None
+    };
+
+    let export_path = Clip::export_path(&config.exports_dir, clip_id);
+    create_clip_export(
+        &path,
+        &export_path,
+        clip.start_time,
+        clip.duration,
+        height,
+        title,
+        chat.as_ref(),
+    )
+    .await?;
+
+    Database::set_clip_exported(
+        get_conn().await?.borrow_mut(),
+        clip_id,
+        title.is_some(),
+        chat.is_some(),
+    )
+    .await?;
+
+    println!("[{}] made clip export in {:?}", clip_id, start.elapsed());
+
+    Ok(())
+}
+
 async fn make_thumbnails(stream_id: i64, path: &Path) -> Result<()> {
     let sections = get_sections_from_file(path, PREVIEW_PER_SECS).await?;
     println!("[{}] sections are: {:?}", stream_id, sections);
 
     let start = Instant::now();
 
-    let thumbnail_path = StreamInfo::thumbnails_path(stream_id);
+    let thumbnail_path =
+        StreamInfo::thumbnails_path(&CONFIG.get().unwrap().thumbnails_dir, stream_id);
     let ts: Vec<_> = sections.iter().map(|(a, _)| *a).collect();
-    let items = create_thumbnails(&path, &thumbnail_path, &ts).await?;
+    let ts = pick_thumbnail_times(path, &ts).await;
+    let thumbnails = create_thumbnails(path, &thumbnail_path, &ts).await?;
+    for (loc, e) in &thumbnails.failures {
+        eprintln!(
+            "[{}] failed to make thumbnail at {}s: {:?}",
+            stream_id, loc, e
+        );
+    }
+    if thumbnails.outputs.is_empty() {
+        return Err(anyhow!(
+            "no thumbnails could be made, {} failed",
+            thumbnails.failures.len()
+        ));
+    }
 
     let db = DB.get().unwrap();
-    let thumbnail_count = items.len() as i64;
+    let thumbnail_count = thumbnails.outputs.len() as i64;
     sqlx::query!(
         "UPDATE streams SET thumbnail_count = ?1 WHERE id = ?2",
         thumbnail_count,
@@ -185,9 +519,10 @@ async fn make_thumbnails(stream_id: i64, path: &Path) -> Result<()> {
     update_cache().await?;
 
     println!(
-        "[{}] made {} thumbnails in {:?}",
+        "[{}] made {} thumbnails ({} failed) in {:?}",
         stream_id,
-        sections.len(),
+        thumbnail_count,
+        thumbnails.failures.len(),
         start.elapsed()
     );
 
@@ -200,14 +535,100 @@ async fn make_scrub_thumbnails(stream_id: i64, path: &Path) -> Result<()> {
 
     let start = Instant::now();
 
-    let thumbnail_path = StreamInfo::scrub_thumbnails_path(stream_id);
-    let ts: Vec<_> = sections.iter().map(|(a, _)| *a).collect();
-    create_thumbnails(&path, &thumbnail_path, &ts).await?;
+    let thumbnail_path =
+        StreamInfo::scrub_thumbnails_path(&CONFIG.get().unwrap().scrub_thumbnails_dir, stream_id);
+    let ts: Vec<_> = sections.iter().map(|(a, _)| *a as f64).collect();
+    let thumbnails = create_thumbnails(path, &thumbnail_path, &ts).await?;
+    for (loc, e) in &thumbnails.failures {
+        eprintln!(
+            "[{}] failed to make scrub thumbnail at {}s: {:?}",
+            stream_id, loc, e
+        );
+    }
+    if thumbnails.outputs.is_empty() {
+        return Err(anyhow!(
+            "no scrub thumbnails could be made, {} failed",
+            thumbnails.failures.len()
+        ));
+    }
 
     println!(
-        "[{}] made {} thumbnails in {:?}",
+        "[{}] made {} scrub thumbnails ({} failed) in {:?}",
         stream_id,
-        sections.len(),
+        thumbnails.outputs.len(),
+        thumbnails.failures.len(),
+        start.elapsed()
+    );
+
+    Ok(())
+}
+
+/// The sprite sheets are made next to the separate scrub thumbnails, which are kept for clients
+/// that don't use the WebVTT track yet.
+async fn make_scrub_sprites(stream_id: i64, path: &Path) -> Result<()> {
+    let start = Instant::now();
+
+    let output_dir =
+        StreamInfo::scrub_thumbnails_path(&CONFIG.get().unwrap().scrub_thumbnails_dir, stream_id);
+    let count = create_scrub_sprites(path, &output_dir).await?;
+
+    let db = DB.get().unwrap();
+    sqlx::query!(
+        "UPDATE streams SET has_scrub_sprites = 1 WHERE id = ?1",
+        stream_id
+    )
+    .execute(&db.pool)
+    .await?;
+    update_cache().await?;
+
+    println!(
+        "[{}] made {} scrub thumbnails in {:?}",
+        stream_id,
+        count,
+        start.elapsed()
+    );
+
+    Ok(())
+}
+
+async fn make_hls(stream_id: i64, path: &Path) -> Result<()> {
+    let height = get_video_height(path).await?;
+    let has_audio = has_audio(path).await?;
+    let mut renditions: Vec<&HlsRendition> = HLS_LADDER
+        .iter()
+        .filter(|r| r.height.map_or(has_audio, |h| h <= height))
+        .collect();
+    if renditions.iter().all(|r| r.height.is_none()) {
+        // smaller than every rendition, just use the lowest one
+        let lowest = HLS_LADDER.iter().rev().find(|r| r.height.is_some());
+        renditions.splice(0..0, lowest);
+    }
+
+    let start = Instant::now();
+
+    let output_dir = StreamInfo::hls_path(&CONFIG.get().unwrap().hls_dir, stream_id);
+    // Start from scratch, so no segments of an older version of the stream are left behind.
+    match remove_dir_all(&output_dir).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    create_hls(path, &output_dir, &renditions, has_audio).await?;
+
+    let names: Vec<&str> = renditions.iter().map(|r| r.name).collect();
+    let names_json = serde_json::to_string(&names)?;
+    sqlx::query!(
+        "UPDATE streams SET hls_renditions = ?1 WHERE id = ?2",
+        names_json,
+        stream_id
+    )
+    .execute(&DB.get().unwrap().pool)
+    .await?;
+    update_cache().await?;
+
+    println!(
+        "[{}] made hls renditions {:?} in {:?}",
+        stream_id,
+        names,
         start.elapsed()
     );
 
@@ -219,9 +640,13 @@ async fn make_clip_thumbnail(clip_id: i64) -> Result<()> {
 
     let start = Instant::now();
 
-    let thumbnail_path = Clip::thumbnail_path(clip_id);
+    let config = CONFIG.get().unwrap();
+    let thumbnail_path = Clip::thumbnail_path(&config.thumbnails_dir, clip_id);
     create_clip_thumbnail(
-        &stream.info.file_name.stream_path(STREAMS_DIR),
+        &stream
+            .info
+            .file_name
+            .stream_path(config.library_dir(&stream.info.library)?),
         &thumbnail_path,
         clip.start_time,
     )
@@ -268,70 +693,452 @@ async fn update_chatspeed(stream_id: i64) -> Result<()> {
     Ok(())
 }
 
-async fn job_watcher(receiver: Arc<sync::Mutex<JobReceiver>>) {
+async fn update_chat_index(stream_id: i64) -> Result<()> {
+    let stream = expect_stream(get_conn().await?.borrow_mut(), stream_id).await?;
+    let lines = get_chat_lines(stream.info).await?;
+    let count = lines.len();
+    Database::set_chat_search_lines(get_conn().await?.borrow_mut(), stream_id, lines).await?;
+
+    println!("[{}] indexed {} chat messages", stream_id, count);
+
+    Ok(())
+}
+
+async fn update_chat_seek_index(stream_id: i64) -> Result<()> {
+    let stream = expect_stream(get_conn().await?.borrow_mut(), stream_id).await?;
+    if !stream.info.has_chat {
+        return Ok(());
+    }
+    build_seek_index(&stream.info).await?;
+
+    println!("[{}] built chat seek index", stream_id);
+
+    Ok(())
+}
+
+/// Queue the jobs that use the hype once the loudness and chatspeed are in. The preview is
+/// usually made before the hype is known, so it is made again. If `other`, the job for the other
+/// half, is still pending, it takes care of it when it finishes instead.
+///
+/// This runs after the job finished, so two jobs finishing at the same time can't both leave it to
+/// the other. They can both do it, the second follow-up jobs supersede the first.
+async fn hype_updated(stream_id: i64, other: Job) -> Result<()> {
+    {
+        let mut conn = get_conn().await?;
+        let model = &CONFIG.get().unwrap().hype;
+        let datapoints = Database::get_hype_datapoints(conn.borrow_mut(), stream_id, model).await?;
+        Database::set_hype_average(conn.borrow_mut(), stream_id, hype_average(&datapoints)).await?;
+    }
+    update_cache().await?;
+
+    let sender = SENDER.get().unwrap();
+    if sender.is_pending(&other).await? {
+        return Ok(());
+    }
+
+    sender.send(Job::Highlights { stream_id }).await?;
+
+    let stream = expect_stream(get_conn().await?.borrow_mut(), stream_id).await?;
+    if !stream.info.has_preview {
+        return Ok(());
+    }
+    let path = stream
+        .info
+        .file_name
+        .stream_path(CONFIG.get().unwrap().library_dir(&stream.info.library)?);
+    sender.send(Job::Preview { stream_id, path }).await
+}
+
+async fn update_highlights(stream_id: i64) -> Result<()> {
+    let mut conn = get_conn().await?;
+    let stream = expect_stream(conn.borrow_mut(), stream_id).await?;
+    let model = &CONFIG.get().unwrap().hype;
+    let hype = Database::get_hype_datapoints(conn.borrow_mut(), stream_id, model).await?;
+    drop(conn);
+
+    let highlights = get_highlights(&stream.info, &hype).await?;
+    Database::set_clip_suggestions(get_conn().await?.borrow_mut(), stream_id, &highlights).await?;
+
+    println!("[{}] found {} highlights", stream_id, highlights.len());
+
+    Ok(())
+}
+
+struct JobInfoRow {
+    id: i64,
+    kind: String,
+    stream_id: Option<i64>,
+    status: String,
+    attempts: i64,
+    last_error: Option<String>,
+    created_at: i64,
+    started_at: Option<i64>,
+    finished_at: Option<i64>,
+}
+
+fn map_job_info(row: JobInfoRow) -> JobInfo {
+    JobInfo {
+        id: row.id,
+        kind: row.kind,
+        stream_id: row.stream_id,
+        status: row.status,
+        attempts: row.attempts,
+        last_error: row.last_error,
+        created_at: timestamp(row.created_at),
+        started_at: row.started_at.map(timestamp),
+        finished_at: row.finished_at.map(timestamp),
+    }
+}
+
+pub async fn get_jobs_overview() -> Result<JobsOverview> {
+    let db = DB.get().unwrap();
+
+    let mut queued: HashMap<String, i64> = Job::KINDS
+        .iter()
+        .map(|kind| (kind.to_string(), 0))
+        .collect();
+    let counts: Vec<(String, i64)> = sqlx::query!(
+        "SELECT kind, COUNT(*) AS count FROM jobs WHERE status = 'queued' GROUP BY kind",
+    )
+    .map(|row| (row.kind, row.count))
+    .fetch_all(&db.pool)
+    .await?;
+    queued.extend(counts);
+
+    let running = sqlx::query_as!(
+        JobInfoRow,
+        r#"
+        SELECT id, kind, stream_id, status, attempts, last_error, created_at, started_at, finished_at
+        FROM jobs
+        WHERE status = 'running'
+        ORDER BY started_at
+        "#,
+    )
+    .map(map_job_info)
+        .fetch_all(&db.pool)
+        .await?;
+
+    let failed = sqlx::query_as!(
+        JobInfoRow,
+        r#"
+        SELECT id, kind, stream_id, status, attempts, last_error, created_at, started_at, finished_at
+        FROM jobs
+        WHERE status = 'failed'
+        ORDER BY finished_at DESC
+        LIMIT ?1
+        "#,
+        RECENT_FAILURES,
+    )
+    .map(map_job_info)
+    .fetch_all(&db.pool)
+    .await?;
+
+    Ok(JobsOverview {
+        queued,
+        running,
+        failed,
+    })
+}
+
+struct ClaimedJob {
+    id: i64,
+    /// How often the job has been started, including this time.
+    attempts: i64,
+    job: Job,
+}
+
+/// Claim the next queued job that is due, marking it as running. Returns `None` if there are no
+/// such jobs.
+async fn claim_job() -> Result<Option<ClaimedJob>> {
+    let now = Utc::now().timestamp();
+
+    // A single statement is atomic, so two workers can never claim the same job.
+    let row = sqlx::query!(
+        r#"
+        UPDATE jobs
+        SET
+            status = 'running',
+            attempts = attempts + 1,
+            started_at = ?1,
+            finished_at = NULL
+        WHERE id = (
+            SELECT id
+            FROM jobs
+            WHERE status = 'queued'
+                AND (run_after IS NULL OR run_after <= ?1)
+            ORDER BY priority, id
+            LIMIT 1
+        )
+        RETURNING id, payload, attempts
+        "#,
+        now,
+    )
+    .fetch_optional(&DB.get().unwrap().pool)
+    .await?;
+
+    let (id, payload, attempts) = match row {
+        None => return Ok(None),
+        Some(row) => (row.id, row.payload, row.attempts),
+    };
+
+    match serde_json::from_str(&payload) {
+        Ok(job) => Ok(Some(ClaimedJob { id, attempts, job })),
+        Err(e) => {
+            finish_job(id, attempts, Err(anyhow!("invalid job payload: {}", e))).await?;
+            Ok(None)
+        }
+    }
+}
+
+/// How long until the first job that is waiting to be retried is due.
+async fn next_retry_in() -> Result<Option<Duration>> {
+    let run_after = sqlx::query!(
+        r#"SELECT MIN(run_after) AS "run_after?: i64" FROM jobs WHERE status = 'queued'"#
+    )
+    .map(|row| row.run_after)
+    .fetch_one(&DB.get().unwrap().pool)
+    .await?;
+
+    let now = Utc::now().timestamp();
+    Ok(run_after.map(|ts| Duration::from_secs((ts - now).max(1) as u64)))
+}
+
+/// Whether a job that failed with this error might succeed when it is tried again. Failing
+/// external commands decide for themselves, IO errors are transient unless a file is missing,
+/// can't be accessed or is broken, everything else (missing streams, invalid data, ...) is
+/// permanent.
+fn is_transient(e: &anyhow::Error) -> bool {
+    for cause in e.chain() {
+        if let Some(e) = cause.downcast_ref::<CommandError>() {
+            return e.is_transient();
+        }
+        if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            return !matches!(
+                e.kind(),
+                ErrorKind::NotFound | ErrorKind::PermissionDenied | ErrorKind::InvalidData
+            );
+        }
+    }
+    false
+}
+
+/// Record the result of a job. Transient failures are queued again with exponential backoff,
+/// until the job has been attempted `job_max_attempts` times.
+async fn finish_job(id: i64, attempts: i64, res: Result<()>) -> Result<()> {
+    let config = CONFIG.get().unwrap();
+    let now = Utc::now().timestamp();
+
+    let (status, error, run_after) = match res {
+        Ok(()) => ("done", None, None),
+        Err(e) if is_transient(&e) && attempts < config.job_max_attempts => {
+            let delay = config.job_retry_base_secs << (attempts - 1).clamp(0, 16);
+            println!(
+                "job {} failed (attempt {}/{}), retrying in {}s",
+                id, attempts, config.job_max_attempts, delay
+            );
+            ("queued", Some(format!("{:?}", e)), Some(now + delay as i64))
+        }
+        Err(e) => ("failed", Some(format!("{:?}", e)), None),
+    };
+
+    sqlx::query!(
+        "UPDATE jobs SET status = ?1, last_error = ?2, finished_at = ?3, run_after = ?4 WHERE id = ?5 AND status = 'running'",
+        status,
+        error,
+        now,
+        run_after,
+        id,
+    )
+    .execute(&DB.get().unwrap().pool)
+    .await?;
+    Ok(())
+}
+
+/// Returns the reason if the job is disabled by a processing override for its stream.
+async fn disabled_reason(job: &Job) -> Result<Option<String>> {
+    let stream_id = match job.stream_id() {
+        None => return Ok(None),
+        Some(id) => id,
+    };
+
+    let reason =
+        Database::get_processing_override(get_conn().await?.borrow_mut(), stream_id, job.kind())
+            .await?;
+    Ok(reason.map(|reason| reason.unwrap_or_else(|| String::from("no reason given"))))
+}
+
+/// Whether a claimed job wasn't cancelled or superseded in the meantime.
+async fn is_still_running(id: i64) -> Result<bool> {
+    let row = sqlx::query!("SELECT status FROM jobs WHERE id = ?1", id)
+        .fetch_optional(&DB.get().unwrap().pool)
+        .await?;
+    Ok(row.is_some_and(|row| row.status == "running"))
+}
+
+/// Mark a job that was disabled after it was queued as cancelled.
+async fn skip_job(id: i64, reason: String) -> Result<()> {
+    let now = Utc::now().timestamp();
+    let error = format!("disabled for this stream: {}", reason);
+    sqlx::query!(
+        "UPDATE jobs SET status = 'cancelled', last_error = ?1, finished_at = ?2 WHERE id = ?3",
+        error,
+        now,
+        id,
+    )
+    .execute(&DB.get().unwrap().pool)
+    .await?;
+    Ok(())
+}
+
+async fn run_job(job: Job) -> Result<()> {
+    match job {
+        Job::Preview { stream_id, path } => make_preview(stream_id, path).await,
+        Job::Thumbnails { stream_id, path } => {
+            try {
+                make_thumbnails(stream_id, &path).await?;
+                make_scrub_thumbnails(stream_id, &path).await?;
+                make_scrub_sprites(stream_id, &path).await?;
+            }
+        }
+        Job::ScrubSprites { stream_id, path } => make_scrub_sprites(stream_id, &path).await,
+        Job::ClipPreview { clip_id } => make_clip_preview(clip_id).await,
+        Job::ClipThumbnail { clip_id } => make_clip_thumbnail(clip_id).await,
+        Job::Loudness { stream_id } => update_loudness(stream_id).await,
+        Job::Chatspeed { stream_id } => update_chatspeed(stream_id).await,
+        Job::Hls { stream_id, path } => make_hls(stream_id, &path).await,
+        Job::ClipExport {
+            clip_id,
+            burn_title,
+            burn_chat,
+        } => make_clip_export(clip_id, burn_title, burn_chat).await,
+        Job::Highlights { stream_id } => update_highlights(stream_id).await,
+        Job::ChatIndex { stream_id } => update_chat_index(stream_id).await,
+        Job::ChatSeekIndex { stream_id } => update_chat_seek_index(stream_id).await,
+    }
+}
+
+/// Work that depends on other jobs too, done once a job succeeded and is marked as done.
+async fn job_finished(job: Job) -> Result<()> {
+    match job {
+        Job::Loudness { stream_id } => hype_updated(stream_id, Job::Chatspeed { stream_id }).await,
+        Job::Chatspeed { stream_id } => hype_updated(stream_id, Job::Loudness { stream_id }).await,
+        _ => Ok(()),
+    }
+}
+
+async fn job_watcher(notify: Arc<Notify>) {
     loop {
-        let job = match {
-            let mut receiver = receiver.lock().await;
-            receiver.recv().await
-        } {
-            None => break,
-            Some(j) => j,
+        let ClaimedJob { id, attempts, job } = match claim_job().await {
+            Ok(Some(j)) => j,
+            Ok(None) => {
+                let wait = match next_retry_in().await {
+                    Ok(Some(wait)) => wait.min(POLL_INTERVAL),
+                    _ => POLL_INTERVAL,
+                };
+                let _ = tokio::time::timeout(wait, notify.notified()).await;
+                continue;
+            }
+            Err(e) => {
+                eprintln!("error while claiming job: {:?}", e);
+                tokio::time::sleep(POLL_INTERVAL).await;
+                continue;
+            }
         };
 
-        let res = match job {
-            Job::Preview { stream_id, path } => make_preview(stream_id, path).await,
-            Job::Thumbnails { stream_id, path } => {
-                try {
-                    make_thumbnails(stream_id, &path).await?;
-                    make_scrub_thumbnails(stream_id, &path).await?;
+        // Registered before anything else is awaited, superseding or cancelling the job from now
+        // on stops it. If that happened since it was claimed, it is no longer running.
+        let cancel = Arc::new(Notify::new());
+        RUNNING_JOBS.lock().unwrap().insert(id, cancel.clone());
+        match is_still_running(id).await {
+            Ok(true) => {}
+            Ok(false) => {
+                RUNNING_JOBS.lock().unwrap().remove(&id);
+                println!("job {} was stopped before it started", id);
+                continue;
+            }
+            Err(e) => eprintln!("error while checking status of job {}: {:?}", id, e),
+        }
+
+        match disabled_reason(&job).await {
+            Ok(Some(reason)) => {
+                RUNNING_JOBS.lock().unwrap().remove(&id);
+                if let Err(e) = skip_job(id, reason).await {
+                    eprintln!("error while skipping job {}: {:?}", id, e);
                 }
+                continue;
+            }
+            Ok(None) => {}
+            Err(e) => eprintln!("error while checking overrides for job {}: {:?}", id, e),
+        }
+
+        let res = tokio::select! {
+            res = run_job(job.clone()) => Some(res),
+            _ = cancel.notified() => None,
+        };
+        RUNNING_JOBS.lock().unwrap().remove(&id);
+
+        let res = match res {
+            None => {
+                println!("stopped job {}", id);
+                continue;
             }
-            Job::ClipPreview { clip_id } => make_clip_preview(clip_id).await,
-            Job::ClipThumbnail { clip_id } => make_clip_thumbnail(clip_id).await,
-            Job::Loudness { stream_id } => update_loudness(stream_id).await,
-            Job::Chatspeed { stream_id } => update_chatspeed(stream_id).await,
+            Some(res) => res,
         };
-        if let Err(e) = res {
-            eprintln!("error while executing job: {:?}", e);
+        if let Err(e) = &res {
+            eprintln!("error while executing job {}: {:?}", id, e);
+        }
+        let succeeded = res.is_ok();
+        if let Err(e) = finish_job(id, attempts, res).await {
+            eprintln!("error while finishing job {}: {:?}", id, e);
+            continue;
+        }
+        if succeeded {
+            if let Err(e) = job_finished(job).await {
+                eprintln!("error while queueing the jobs after job {}: {:?}", id, e);
+            }
         }
     }
 }
 
-pub fn spawn_job_watchers(count: usize) {
-    let (sender, receiver) = {
-        let (thumb_sender, thumb_receiver) = sync::mpsc::unbounded_channel();
-        let (prev_sender, prev_receiver) = sync::mpsc::unbounded_channel();
-        let (clip_thumb_sender, clip_thumb_receiver) = sync::mpsc::unbounded_channel();
-        let (clip_prev_sender, clip_prev_receiver) = sync::mpsc::unbounded_channel();
-        let (loudness_sender, loudness_receiver) = sync::mpsc::unbounded_channel();
-        let (chatspeed_sender, chatspeed_receiver) = sync::mpsc::unbounded_channel();
-
-        let sender = JobSender {
-            thumbnail_jobs: thumb_sender,
-            preview_jobs: prev_sender,
-            clip_thumbnail_jobs: clip_thumb_sender,
-            clip_preview_jobs: clip_prev_sender,
-            loudness_jobs: loudness_sender,
-            chatspeed_jobs: chatspeed_sender,
-        };
+/// Set up [`SENDER`] so jobs can be queued. Jobs are only picked up once
+/// [`spawn_job_watchers`] is called.
+pub fn init_job_queue() {
+    okky!(
+        SENDER,
+        JobSender {
+            notify: Arc::new(Notify::new()),
+        }
+    );
+}
 
-        let receiver = JobReceiver {
-            thumbnail_jobs: thumb_receiver,
-            preview_jobs: prev_receiver,
-            clip_thumbnail_jobs: clip_thumb_receiver,
-            clip_preview_jobs: clip_prev_receiver,
-            loudness_jobs: loudness_receiver,
-            chatspeed_jobs: chatspeed_receiver,
-        };
+/// Requeue the jobs that were running when we were stopped, clean up old finished jobs and spawn
+/// `count` workers.
+pub async fn spawn_job_watchers(count: usize) -> Result<()> {
+    let db = DB.get().unwrap();
 
-        (sender, receiver)
-    };
-    let receiver = Arc::new(sync::Mutex::new(receiver));
-    okky!(SENDER, sender);
+    let resumed = sqlx::query!("UPDATE jobs SET status = 'queued' WHERE status = 'running'")
+        .execute(&db.pool)
+        .await?
+        .rows_affected();
+    if resumed > 0 {
+        println!("resuming {} interrupted jobs", resumed);
+    }
 
-    itertools::repeat_n(receiver, count).for_each(|receiver| {
+    let cutoff = (Utc::now() - KEEP_DONE_JOBS).timestamp();
+    sqlx::query!(
+        "DELETE FROM jobs WHERE status = 'done' AND finished_at < ?1",
+        cutoff
+    )
+    .execute(&db.pool)
+    .await?;
+
+    let notify = SENDER.get().unwrap().notify.clone();
+    for _ in 0..count {
+        let notify = notify.clone();
         tokio::spawn(async move {
-            job_watcher(receiver).await;
+            job_watcher(notify).await;
         });
-    });
+    }
+
+    Ok(())
 }
@@ -1,17 +1,19 @@
-use crate::STREAMS_DIR;
+use crate::CONFIG;
 
 use std::{collections::HashMap, iter::Sum, panic};
 
 use chrono::{DateTime, Duration, Utc};
 use tokio::process::Command;
 
+use streamwatch_shared::functions::run_command;
+
 use itertools::Itertools;
 
 use anyhow::Result;
 
 use regex::Regex;
 
-use streamwatch_shared::types::{StreamFileName, StreamInfo};
+use streamwatch_shared::types::StreamInfo;
 
 struct LoudnessInformation {
     pub momentary: f32,
@@ -51,21 +53,21 @@ impl Sum for LoudnessInformation {
     }
 }
 
-async fn _get_loudness_points(
-    stream_filename: &StreamFileName,
-) -> Result<Vec<(f32, LoudnessInformation)>> {
+async fn _get_loudness_points(stream: &StreamInfo) -> Result<Vec<(f32, LoudnessInformation)>> {
+    let streams_dir = CONFIG.get().unwrap().library_dir(&stream.library)?;
+
     let mut cmd = {
         let mut cmd = Command::new("nice");
         cmd.args(&["-n10", "ffmpeg"]);
         cmd.args(&["-hide_banner", "-nostats"]);
         cmd.arg("-i");
-        cmd.arg(stream_filename.stream_path(STREAMS_DIR));
+        cmd.arg(stream.file_name.stream_path(streams_dir));
         cmd.args(&["-vn", "-filter_complex", "ebur128", "-f", "null", "-"]);
         cmd
     };
 
     let output = {
-        let output = cmd.output().await?;
+        let output = run_command(&mut cmd).await?;
         String::from_utf8(output.stderr)?
     };
 
@@ -122,7 +124,7 @@ pub struct LoudnessDatapoint {
 pub async fn get_loudness_points(stream: &StreamInfo) -> Result<Vec<LoudnessDatapoint>> {
     let ts = stream.timestamp;
 
-    let res = _get_loudness_points(&stream.file_name)
+    let res = _get_loudness_points(stream)
         .await?
         .into_iter()
         .group_by(|(pos, _)| pos.round() as i64)
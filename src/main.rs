@@ -5,22 +5,32 @@
 #![feature(async_closure)]
 
 mod chat;
+mod chat_index;
+mod chat_overlay;
 mod chatspeed;
+mod config;
 mod create_preview;
 mod db;
+mod highlights;
+mod hype;
 //mod hypegraph;
 mod job_handler;
 mod loudness;
 mod migrations;
+mod password;
+mod remux;
 mod scan;
 mod util;
 mod volume;
+mod watcher;
 mod watchparty;
 mod web;
 
 use crate::chat::cache_pruner;
-use crate::job_handler::spawn_job_watchers;
+use crate::config::Config;
+use crate::job_handler::{init_job_queue, spawn_job_watchers};
 use crate::scan::generate_missing_info;
+use crate::watcher::watch_libraries;
 use crate::web::run_server;
 
 use anyhow::Result;
@@ -30,8 +40,7 @@ use serde_json::value::{to_raw_value, RawValue};
 use tokio::sync::RwLock;
 use util::get_conn;
 
-const PREVIEW_WORKERS: usize = 4;
-pub const STREAMS_DIR: &str = "/streams/lekkerspelen";
+pub static CONFIG: OnceCell<Config> = OnceCell::new();
 
 pub static DB: OnceCell<db::Database> = OnceCell::new();
 
@@ -43,7 +52,7 @@ pub async fn update_cache() -> anyhow::Result<()> {
     let cache = STREAMS_JSON_CACHE.get().unwrap();
     let mut cache = cache.write().await;
 
-    let streams = Database::get_streams(&mut conn).await?;
+    let streams = Database::get_streams(&mut conn, None).await?;
     *cache = to_raw_value(&streams)?;
 
     Ok(())
@@ -53,12 +62,17 @@ pub async fn update_cache() -> anyhow::Result<()> {
 async fn main() -> Result<()> {
     env_logger::init();
 
-    okky!(DB, db::Database::new().await?);
+    okky!(CONFIG, Config::load().await?);
+    let config = CONFIG.get().unwrap();
 
-    spawn_job_watchers(PREVIEW_WORKERS);
+    okky!(DB, db::Database::new(&config.database_url).await?);
+
+    init_job_queue();
 
     migrations::run().await.unwrap();
 
+    spawn_job_watchers(config.preview_workers).await?;
+
     generate_missing_info().await?;
 
     tokio::spawn(async {
@@ -67,11 +81,19 @@ async fn main() -> Result<()> {
 
     okky!(STREAMS_JSON_CACHE, {
         let mut conn = get_conn().await.unwrap();
-        let streams = Database::get_streams(&mut conn).await.unwrap();
+        let streams = Database::get_streams(&mut conn, None).await.unwrap();
         let val = to_raw_value(&streams).unwrap();
         RwLock::new(val)
     });
 
+    if config.watch_libraries {
+        tokio::spawn(async {
+            if let Err(e) = watch_libraries().await {
+                eprintln!("error while watching libraries: {:?}", e);
+            }
+        });
+    }
+
     run_server().await;
 
     Ok(())
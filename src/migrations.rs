@@ -1,12 +1,10 @@
-use std::borrow::BorrowMut;
 use std::ops::DerefMut;
 
-use crate::{
-    db::Database,
-    job_handler::{Job, SENDER},
-    util::get_conn,
-    DB, STREAMS_DIR,
-};
+use crate::{job_handler::Job, CONFIG, DB};
+
+use streamwatch_shared::types::StreamFileName;
+
+use chrono::Utc;
 
 use anyhow::Result;
 
@@ -41,19 +39,27 @@ async fn get_version() -> Result<i64> {
     Ok(res)
 }
 
+/// The streams as `(id, file name)`. Migrations can't use the queries for the current schema,
+/// these columns are in every version of it. Streams from before migration 7 are all in the
+/// default library.
+async fn get_stream_file_names() -> Result<Vec<(i64, StreamFileName)>> {
+    let db = DB.get().unwrap();
+
+    let streams = sqlx::query!("SELECT id, filename FROM streams")
+        .map(|row| (row.id, StreamFileName::from(row.filename)))
+        .fetch_all(&db.pool)
+        .await?;
+    Ok(streams)
+}
+
 async fn three() -> Result<()> {
     let done = version_check!(3);
 
     let db = DB.get().unwrap();
+    let streams_dir = CONFIG.get().unwrap().default_library().path.as_str();
 
-    let streams = Database::get_streams(get_conn().await?.borrow_mut()).await?;
-    for stream in streams {
-        let (datapoints, jumpcuts) = match stream
-            .info
-            .file_name
-            .get_extra_info_from_file(STREAMS_DIR)
-            .await?
-        {
+    for (stream_id, file_name) in get_stream_file_names().await? {
+        let (datapoints, jumpcuts) = match file_name.get_extra_info_from_file(streams_dir).await? {
             None => continue,
             Some((datapoints, jumpcuts)) => (datapoints, jumpcuts),
         };
@@ -64,7 +70,7 @@ async fn three() -> Result<()> {
             sqlx::query(
                 "INSERT INTO stream_datapoints(stream_id, timestamp, title, viewcount) VALUES(?, ?, ?, ?)",
             )
-            .bind(stream.info.id)
+            .bind(stream_id)
             .bind(datapoint.timestamp.timestamp())
             .bind(datapoint.title)
             .bind(datapoint.viewcount)
@@ -74,7 +80,7 @@ async fn three() -> Result<()> {
 
         for jumpcut in jumpcuts {
             sqlx::query("INSERT INTO stream_jumpcuts(stream_id, at, duration) VALUES(?, ?, ?)")
-                .bind(stream.info.id)
+                .bind(stream_id)
                 .bind(jumpcut.at.timestamp())
                 .bind(jumpcut.duration.as_secs() as i64)
                 .execute(tx.deref_mut())
@@ -93,17 +99,18 @@ async fn four() -> Result<()> {
     let done = version_check!(4);
 
     let db = DB.get().unwrap();
+    let streams_dir = CONFIG.get().unwrap().default_library().path.as_str();
 
-    let streams = Database::get_streams(get_conn().await?.borrow_mut()).await?;
+    let streams = get_stream_file_names().await?;
 
     {
         let mut tx = db.pool.begin().await?;
-        for stream in streams {
-            let has_chat = stream.info.file_name.has_chat(STREAMS_DIR).await?;
+        for (stream_id, file_name) in streams {
+            let has_chat = file_name.has_chat(streams_dir).await?;
 
             sqlx::query("UPDATE streams SET has_chat = ? WHERE id = ?")
                 .bind(has_chat)
-                .bind(stream.info.id)
+                .bind(stream_id)
                 .execute(tx.deref_mut())
                 .await?;
         }
@@ -115,19 +122,42 @@ async fn four() -> Result<()> {
     Ok(())
 }
 
+/// Jobs are stored in a table that only exists from migration 10, until then the jobs of a
+/// migration are kept in `meta` under `key`. Saving them again replaces them, so a migration that
+/// is run again after a crash doesn't queue its jobs twice.
+async fn save_jobs(key: &str, jobs: &[Job]) -> Result<()> {
+    let db = DB.get().unwrap();
+
+    let jobs = serde_json::to_string(jobs)?;
+    sqlx::query!(
+        "INSERT OR REPLACE INTO meta (key, value) VALUES (?1, ?2)",
+        key,
+        jobs
+    )
+    .execute(&db.pool)
+    .await?;
+    Ok(())
+}
+
 async fn five() -> Result<()> {
     let done = version_check!(5);
 
-    let clips = Database::get_clips(get_conn().await?.borrow_mut(), None).await?;
+    let db = DB.get().unwrap();
+
+    let clip_ids = sqlx::query!("SELECT id FROM clips")
+        .map(|row| row.id)
+        .fetch_all(&db.pool)
+        .await?;
 
-    let total_count = clips.len();
-    for (i, clip) in clips.into_iter().enumerate() {
+    let mut jobs = vec![];
+    let total_count = clip_ids.len();
+    for (i, clip_id) in clip_ids.into_iter().enumerate() {
         println!("migration 5: clip {}/{}", i + 1, total_count);
 
-        let sender = SENDER.get().unwrap();
-        sender.send(Job::ClipPreview { clip_id: clip.id })?;
-        sender.send(Job::ClipThumbnail { clip_id: clip.id })?;
+        jobs.push(Job::ClipPreview { clip_id });
+        jobs.push(Job::ClipThumbnail { clip_id });
     }
+    save_jobs("migration_5_jobs", &jobs).await?;
 
     done().await?;
 
@@ -137,18 +167,467 @@ async fn five() -> Result<()> {
 async fn six() -> Result<()> {
     let done = version_check!(6);
 
-    let streams = Database::get_streams(get_conn().await?.borrow_mut()).await?;
+    let streams_dir = CONFIG.get().unwrap().default_library().path.as_str();
+
+    let streams = get_stream_file_names().await?;
 
+    let mut jobs = vec![];
     let total_count = streams.len();
-    for (i, s) in streams.into_iter().enumerate() {
+    for (i, (stream_id, file_name)) in streams.into_iter().enumerate() {
         println!("migration 6: stream {}/{}", i + 1, total_count);
 
-        let sender = SENDER.get().unwrap();
-        sender.send(Job::Thumbnails {
-            stream_id: s.info.id,
-            path: s.info.file_name.stream_path(STREAMS_DIR),
-        })?;
+        jobs.push(Job::Thumbnails {
+            stream_id,
+            path: file_name.stream_path(streams_dir),
+        });
     }
+    save_jobs("migration_6_jobs", &jobs).await?;
+
+    done().await?;
+
+    Ok(())
+}
+
+async fn seven() -> Result<()> {
+    let done = version_check!(7);
+
+    let db = DB.get().unwrap();
+    let config = CONFIG.get().unwrap();
+
+    let mut tx = db.pool.begin().await?;
+
+    sqlx::query("ALTER TABLE streams ADD COLUMN library TEXT NOT NULL DEFAULT ''")
+        .execute(tx.deref_mut())
+        .await?;
+
+    // Every stream we know about so far comes from the single streams directory we used to have.
+    sqlx::query("UPDATE streams SET library = ?")
+        .bind(&config.default_library().name)
+        .execute(tx.deref_mut())
+        .await?;
+
+    sqlx::query("CREATE UNIQUE INDEX streams_library_filename ON streams(library, filename)")
+        .execute(tx.deref_mut())
+        .await?;
+
+    tx.commit().await?;
+
+    done().await?;
+
+    Ok(())
+}
+
+async fn eight() -> Result<()> {
+    let done = version_check!(8);
+
+    let db = DB.get().unwrap();
+
+    sqlx::query(
+        r#"
+        CREATE TABLE sessions (
+            token TEXT NOT NULL PRIMARY KEY,
+            user_id INTEGER NOT NULL,
+            created_at INTEGER NOT NULL,
+            expires_at INTEGER NOT NULL,
+
+            FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+        )
+        "#,
+    )
+    .execute(&db.pool)
+    .await?;
+
+    done().await?;
+
+    Ok(())
+}
+
+async fn nine() -> Result<()> {
+    let done = version_check!(9);
+
+    let db = DB.get().unwrap();
+
+    let mut tx = db.pool.begin().await?;
+
+    // Users without a row here are viewers.
+    sqlx::query(
+        r#"
+        CREATE TABLE roles (
+            user_id INTEGER NOT NULL PRIMARY KEY,
+            role TEXT NOT NULL,
+
+            FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+        )
+        "#,
+    )
+    .execute(tx.deref_mut())
+    .await?;
+
+    // The admin account used to be recognised by its name.
+    sqlx::query(
+        "INSERT INTO roles(user_id, role) SELECT id, 'admin' FROM users WHERE username = 'admin'",
+    )
+    .execute(tx.deref_mut())
+    .await?;
+
+    for table in [
+        "games",
+        "game_features",
+        "person_participations",
+        "custom_stream_titles",
+    ] {
+        sqlx::query(&format!(
+            "ALTER TABLE {} ADD COLUMN inserted_by INTEGER REFERENCES users(id)",
+            table
+        ))
+        .execute(tx.deref_mut())
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    done().await?;
+
+    Ok(())
+}
+
+async fn ten() -> Result<()> {
+    let done = version_check!(10);
+
+    let db = DB.get().unwrap();
+
+    let mut tx = db.pool.begin().await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE jobs (
+            id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+            kind TEXT NOT NULL,
+            stream_id INTEGER,
+            payload TEXT NOT NULL,
+            status TEXT NOT NULL,
+            priority INTEGER NOT NULL,
+            attempts INTEGER NOT NULL DEFAULT 0,
+            last_error TEXT,
+            created_at INTEGER NOT NULL,
+            started_at INTEGER,
+            finished_at INTEGER
+        )
+        "#,
+    )
+    .execute(tx.deref_mut())
+    .await?;
+
+    sqlx::query("CREATE INDEX jobs_status_priority ON jobs(status, priority, id)")
+        .execute(tx.deref_mut())
+        .await?;
+
+    // The jobs saved by migrations 5 and 6 are queued together with creating the table.
+    let saved = sqlx::query!(
+        "SELECT key, value FROM meta WHERE key IN ('migration_5_jobs', 'migration_6_jobs')"
+    )
+    .fetch_all(tx.deref_mut())
+    .await?;
+    let now = Utc::now().timestamp();
+    for row in saved {
+        let jobs: Vec<Job> = serde_json::from_str(row.value.as_deref().unwrap_or("[]"))?;
+        for job in jobs {
+            let kind = job.kind();
+            let stream_id = job.stream_id();
+            let payload = serde_json::to_string(&job)?;
+            let priority = job.priority();
+            sqlx::query!(
+                "INSERT INTO jobs (kind, stream_id, payload, status, priority, created_at) VALUES (?1, ?2, ?3, 'queued', ?4, ?5)",
+                kind,
+                stream_id,
+                payload,
+                priority,
+                now,
+            )
+            .execute(tx.deref_mut())
+            .await?;
+        }
+        sqlx::query!("DELETE FROM meta WHERE key = ?1", row.key)
+            .execute(tx.deref_mut())
+            .await?;
+    }
+
+    tx.commit().await?;
+
+    done().await?;
+
+    Ok(())
+}
+
+async fn eleven() -> Result<()> {
+    let done = version_check!(11);
+
+    let db = DB.get().unwrap();
+
+    // Jobs that failed with a transient error are queued again, but not picked up before this
+    // time.
+    sqlx::query("ALTER TABLE jobs ADD COLUMN run_after INTEGER")
+        .execute(&db.pool)
+        .await?;
+
+    done().await?;
+
+    Ok(())
+}
+
+async fn twelve() -> Result<()> {
+    let done = version_check!(12);
+
+    let db = DB.get().unwrap();
+
+    let mut tx = db.pool.begin().await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE stream_processing_overrides (
+            stream_id INTEGER NOT NULL,
+            kind TEXT NOT NULL,
+            reason TEXT,
+            inserted_at INTEGER NOT NULL,
+            inserted_by INTEGER REFERENCES users(id),
+
+            PRIMARY KEY (stream_id, kind)
+        )
+        "#,
+    )
+    .execute(tx.deref_mut())
+    .await?;
+
+    // These used to be hard-coded.
+    sqlx::query(
+        r#"
+        INSERT INTO stream_processing_overrides
+            (stream_id, kind, reason, inserted_at)
+        VALUES
+            (1170, 'preview', 'blacklisted for preview generation', strftime('%s', 'now')),
+            (1174, 'preview', 'blacklisted for preview generation', strftime('%s', 'now')),
+            (614, 'chatspeed', 'chat is skipped for this stream', strftime('%s', 'now'))
+        "#,
+    )
+    .execute(tx.deref_mut())
+    .await?;
+
+    tx.commit().await?;
+
+    done().await?;
+
+    Ok(())
+}
+
+async fn thirteen() -> Result<()> {
+    let done = version_check!(13);
+
+    let db = DB.get().unwrap();
+
+    sqlx::query("CREATE INDEX jobs_kind_stream ON jobs(kind, stream_id, status)")
+        .execute(&db.pool)
+        .await?;
+
+    done().await?;
+
+    Ok(())
+}
+
+async fn fourteen() -> Result<()> {
+    let done = version_check!(14);
+
+    let db = DB.get().unwrap();
+
+    // JSON list of the names of the generated HLS renditions.
+    sqlx::query("ALTER TABLE streams ADD COLUMN hls_renditions TEXT")
+        .execute(&db.pool)
+        .await?;
+
+    done().await?;
+
+    Ok(())
+}
+
+async fn fifteen() -> Result<()> {
+    let done = version_check!(15);
+
+    let db = DB.get().unwrap();
+
+    let mut tx = db.pool.begin().await?;
+
+    // Set when the full quality export of the clip is ready, cleared when the clip is changed.
+    sqlx::query("ALTER TABLE clips ADD COLUMN exported_at INTEGER")
+        .execute(tx.deref_mut())
+        .await?;
+    sqlx::query("ALTER TABLE clips ADD COLUMN export_has_title INTEGER NOT NULL DEFAULT 0")
+        .execute(tx.deref_mut())
+        .await?;
+
+    tx.commit().await?;
+
+    done().await?;
+
+    Ok(())
+}
+
+async fn sixteen() -> Result<()> {
+    let done = version_check!(16);
+
+    let db = DB.get().unwrap();
+
+    let mut tx = db.pool.begin().await?;
+
+    sqlx::query("ALTER TABLE clips ADD COLUMN export_has_chat INTEGER NOT NULL DEFAULT 0")
+        .execute(tx.deref_mut())
+        .await?;
+
+    // Exports that are still queued need the new field to be read.
+    sqlx::query(
+        "UPDATE jobs SET payload = json_set(payload, '$.burn_chat', json('false')) WHERE kind = 'clip_export'",
+    )
+    .execute(tx.deref_mut())
+    .await?;
+
+    tx.commit().await?;
+
+    done().await?;
+
+    Ok(())
+}
+
+async fn seventeen() -> Result<()> {
+    let done = version_check!(17);
+
+    let db = DB.get().unwrap();
+
+    // Scrub thumbnails used to be separate files, streams that don't have sprite sheets yet get
+    // them regenerated on startup.
+    sqlx::query("ALTER TABLE streams ADD COLUMN has_scrub_sprites INTEGER NOT NULL DEFAULT 0")
+        .execute(&db.pool)
+        .await?;
+
+    done().await?;
+
+    Ok(())
+}
+
+async fn eighteen() -> Result<()> {
+    let done = version_check!(18);
+
+    let db = DB.get().unwrap();
+
+    let mut tx = db.pool.begin().await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE clip_suggestions (
+            id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+            stream_id INTEGER NOT NULL REFERENCES streams(id) ON DELETE CASCADE,
+            start_time INTEGER NOT NULL,
+            duration INTEGER NOT NULL,
+            confidence REAL NOT NULL,
+            title TEXT,
+            status TEXT NOT NULL DEFAULT 'open',
+            clip_id INTEGER REFERENCES clips(id) ON DELETE SET NULL,
+            created_at INTEGER NOT NULL
+        )
+        "#,
+    )
+    .execute(tx.deref_mut())
+    .await?;
+
+    sqlx::query("CREATE INDEX clip_suggestions_stream_id ON clip_suggestions(stream_id)")
+        .execute(tx.deref_mut())
+        .await?;
+
+    // Streams that were processed before highlight detection existed get it run on startup.
+    sqlx::query("ALTER TABLE streams ADD COLUMN highlights_detected INTEGER NOT NULL DEFAULT 0")
+        .execute(tx.deref_mut())
+        .await?;
+
+    tx.commit().await?;
+
+    done().await?;
+
+    Ok(())
+}
+
+async fn nineteen() -> Result<()> {
+    let done = version_check!(19);
+
+    let db = DB.get().unwrap();
+
+    // Used to be computed by streams_view, it's filled in on startup.
+    sqlx::query("ALTER TABLE streams ADD COLUMN hype_average REAL")
+        .execute(&db.pool)
+        .await?;
+
+    done().await?;
+
+    Ok(())
+}
+
+async fn twenty() -> Result<()> {
+    let done = version_check!(20);
+
+    let db = DB.get().unwrap();
+
+    let mut tx = db.pool.begin().await?;
+
+    // Filled in by the chat_index job. A virtual table can't have foreign keys, the rows of a
+    // stream are removed together with it.
+    sqlx::query(
+        r#"
+        CREATE VIRTUAL TABLE chat_search USING fts5(
+            text,
+            author,
+            stream_id UNINDEXED,
+            ts UNINDEXED,
+            tokenize = 'unicode61 remove_diacritics 2'
+        )
+        "#,
+    )
+    .execute(tx.deref_mut())
+    .await?;
+
+    sqlx::query("ALTER TABLE streams ADD COLUMN chat_indexed INTEGER NOT NULL DEFAULT 0")
+        .execute(tx.deref_mut())
+        .await?;
+
+    tx.commit().await?;
+
+    done().await?;
+
+    Ok(())
+}
+
+async fn twenty_one() -> Result<()> {
+    let done = version_check!(21);
+
+    let db = DB.get().unwrap();
+
+    let mut tx = db.pool.begin().await?;
+
+    // Clip jobs were looked up through their payload, which can't use an index.
+    sqlx::query("ALTER TABLE jobs ADD COLUMN clip_id INTEGER")
+        .execute(tx.deref_mut())
+        .await?;
+
+    sqlx::query(
+        r#"
+        UPDATE jobs
+        SET clip_id = json_extract(payload, '$.clip_id')
+        WHERE kind IN ('clip_preview', 'clip_thumbnail', 'clip_export')
+        "#,
+    )
+    .execute(tx.deref_mut())
+    .await?;
+
+    sqlx::query("CREATE INDEX jobs_kind_clip ON jobs(kind, clip_id, status)")
+        .execute(tx.deref_mut())
+        .await?;
+
+    tx.commit().await?;
 
     done().await?;
 
@@ -160,6 +639,21 @@ pub async fn run() -> Result<()> {
     four().await?;
     five().await?;
     six().await?;
+    seven().await?;
+    eight().await?;
+    nine().await?;
+    ten().await?;
+    eleven().await?;
+    twelve().await?;
+    thirteen().await?;
+    fourteen().await?;
+    fifteen().await?;
+    sixteen().await?;
+    seventeen().await?;
+    eighteen().await?;
+    nineteen().await?;
+    twenty().await?;
+    twenty_one().await?;
 
     Ok(())
 }
@@ -0,0 +1,38 @@
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+
+use rand_core::OsRng;
+
+use tokio::task::spawn_blocking;
+
+use anyhow::{anyhow, Result};
+
+/// Hash the password into a PHC string. This is slow on purpose, so it runs on the blocking
+/// thread pool.
+pub async fn hash_password(password: String) -> Result<String> {
+    spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|e| anyhow!("error hashing password: {}", e))?;
+        Ok(hash.to_string())
+    })
+    .await?
+}
+
+/// Whether `stored` is a password hash, as opposed to a plaintext password from before we hashed
+/// passwords.
+pub fn is_hash(stored: &str) -> bool {
+    PasswordHash::new(stored).is_ok()
+}
+
+/// Verify `password` against the PHC string `hash`.
+pub async fn verify_password(password: String, hash: String) -> Result<bool> {
+    spawn_blocking(move || {
+        let hash = PasswordHash::new(&hash).map_err(|e| anyhow!("invalid password hash: {}", e))?;
+        Ok(Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok())
+    })
+    .await?
+}
@@ -0,0 +1,91 @@
+use std::io;
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+
+use tokio::io::AsyncReadExt;
+use tokio::process::Command;
+use tokio_util::io::ReaderStream;
+
+use futures::{future, stream, Stream, StreamExt};
+use warp::hyper::body::Bytes;
+
+use streamwatch_shared::functions::{stderr_tail, CommandError};
+
+use anyhow::{anyhow, Result};
+
+/// Remux the recording at `path` into a fragmented mp4, starting at the keyframe before `start`.
+/// The streams are copied, not re-encoded, so this is cheap enough to do for every viewer.
+///
+/// ffmpeg writes to the returned stream as it goes, and is killed when the stream is dropped.
+/// This waits for its first output, so a remux that fails right away is an error. If it fails
+/// later on, the stream ends with an error instead of just ending.
+pub async fn remux_to_fmp4(
+    path: &Path,
+    start: Duration,
+) -> Result<impl Stream<Item = io::Result<Bytes>> + Send + 'static> {
+    let mut child = Command::new("ffmpeg")
+        .args(["-hide_banner", "-loglevel", "error"])
+        .arg("-ss")
+        .arg(start.as_secs_f64().to_string())
+        .arg("-i")
+        .arg(path.as_os_str())
+        .args([
+            "-map",
+            "0:v:0",
+            "-map",
+            "0:a:0?",
+            "-c",
+            "copy",
+            "-f",
+            "mp4",
+            "-movflags",
+            "frag_keyframe+empty_moov+default_base_moof",
+            "pipe:1",
+        ])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| anyhow!("ffmpeg has no stdout"))?;
+    let mut stderr = child
+        .stderr
+        .take()
+        .ok_or_else(|| anyhow!("ffmpeg has no stderr"))?;
+
+    // Read on the side, so ffmpeg never blocks on a full stderr pipe.
+    let stderr = tokio::spawn(async move {
+        let mut buf = vec![];
+        let _ = stderr.read_to_end(&mut buf).await;
+        buf
+    });
+
+    // The child is kept around for as long as the stream lives, so it is killed when the client
+    // goes away.
+    let exit = stream::once(async move {
+        let status = child.wait().await?;
+        if status.success() {
+            return Ok(None);
+        }
+        let error = CommandError::Failed {
+            program: String::from("ffmpeg"),
+            status,
+            stderr: stderr_tail(&stderr.await.unwrap_or_default()),
+        };
+        eprintln!("remux failed: {}", error);
+        Err(io::Error::other(error))
+    })
+    .filter_map(|res| future::ready(res.transpose()));
+
+    let mut body = Box::pin(ReaderStream::new(stdout).chain(exit));
+    let first = match body.next().await {
+        None => vec![],
+        Some(chunk) => vec![chunk?],
+    };
+    Ok(stream::iter(first.into_iter().map(Ok)).chain(body))
+}
@@ -1,7 +1,10 @@
+use crate::chat::needs_seek_index;
+use crate::config::Library;
 use crate::db::Database;
+use crate::hype::hype_average;
 use crate::job_handler::{Job, SENDER};
 use crate::util::{get_conn, timestamp};
-use crate::{update_cache, DB, STREAMS_DIR};
+use crate::{update_cache, CONFIG, DB};
 
 use streamwatch_shared::functions::{get_video_duration, parse_filename};
 use streamwatch_shared::types::{
@@ -10,17 +13,21 @@ use streamwatch_shared::types::{
 
 use std::borrow::BorrowMut;
 use std::collections::HashMap;
+use std::io::ErrorKind;
 use std::ops::DerefMut;
 use std::path::Path;
 use std::time::Duration;
 
-use tokio::fs::{read_dir, remove_dir_all, remove_file};
+use tokio::fs::{metadata, read_dir, remove_dir_all, remove_file};
+use tokio::sync::Mutex;
 use tokio_stream::wrappers::ReadDirStream;
 
 use futures::stream::{iter, StreamExt, TryStreamExt};
 
 use chrono::Utc;
 
+use once_cell::sync::Lazy;
+
 use anyhow::{bail, Result};
 
 macro_rules! log_err {
@@ -37,22 +44,80 @@ where
     E: sqlx::Executor<'c, Database = sqlx::sqlite::Sqlite>,
 {
     sqlx::query!(
-        "UPDATE streams SET thumbnail_count=0, preview_count=0 WHERE id = ?1",
+        "UPDATE streams SET thumbnail_count=0, preview_count=0, has_scrub_sprites=0 WHERE id = ?1",
         stream_id,
     )
     .execute(executor)
     .await?;
 
-    log_err!(remove_file(StreamInfo::preview_path(stream_id)).await);
-    log_err!(remove_dir_all(StreamInfo::thumbnails_path(stream_id)).await);
+    let config = CONFIG.get().unwrap();
+    let preview_path = StreamInfo::preview_path(&config.previews_dir, stream_id);
+    let thumbnails_path = StreamInfo::thumbnails_path(&config.thumbnails_dir, stream_id);
+    log_err!(remove_file(preview_path).await);
+    log_err!(remove_dir_all(thumbnails_path).await);
 
     Ok(())
 }
 
-async fn handle_new_stream(path: &Path, file_name: String, file_size: i64) -> Result<()> {
-    let db = DB.get().unwrap();
+pub async fn remove_hls<'c, E>(executor: E, stream_id: i64) -> Result<()>
+where
+    E: sqlx::Executor<'c, Database = sqlx::sqlite::Sqlite>,
+{
+    sqlx::query!(
+        "UPDATE streams SET hls_renditions = NULL WHERE id = ?1",
+        stream_id
+    )
+    .execute(executor)
+    .await?;
+
+    let hls_path = StreamInfo::hls_path(&CONFIG.get().unwrap().hls_dir, stream_id);
+    match remove_dir_all(hls_path).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => eprintln!("error: {}", e),
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Queue the jobs that generate everything we derive from a stream file.
+async fn queue_stream_jobs(stream_id: i64, path: &Path) -> Result<()> {
     let sender = SENDER.get().unwrap();
 
+    sender
+        .send(Job::Thumbnails {
+            stream_id,
+            path: path.to_owned(),
+        })
+        .await?;
+    sender
+        .send(Job::Preview {
+            stream_id,
+            path: path.to_owned(),
+        })
+        .await?;
+    sender.send(Job::Loudness { stream_id }).await?;
+    sender.send(Job::Chatspeed { stream_id }).await?;
+    sender.send(Job::ChatIndex { stream_id }).await?;
+    sender.send(Job::ChatSeekIndex { stream_id }).await?;
+    if CONFIG.get().unwrap().generate_hls {
+        sender
+            .send(Job::Hls {
+                stream_id,
+                path: path.to_owned(),
+            })
+            .await?;
+    }
+
+    Ok(())
+}
+
+async fn handle_new_stream(
+    library: &Library,
+    path: &Path,
+    file_name: String,
+    file_size: i64,
+) -> Result<()> {
+    let db = DB.get().unwrap();
     let file_name = StreamFileName::from(file_name);
 
     let timestamp = match parse_filename(path) {
@@ -71,7 +136,7 @@ async fn handle_new_stream(path: &Path, file_name: String, file_size: i64) -> Re
     };
 
     let (datapoints, jumpcuts) = file_name
-        .get_extra_info_from_file(STREAMS_DIR)
+        .get_extra_info_from_file(&library.path)
         .await?
         .unwrap_or((vec![], vec![]));
 
@@ -79,7 +144,7 @@ async fn handle_new_stream(path: &Path, file_name: String, file_size: i64) -> Re
 
     let stream_id: i64 = {
         let duration = duration.as_secs_f64();
-        let has_chat = file_name.has_chat(STREAMS_DIR).await?;
+        let has_chat = file_name.has_chat(&library.path).await?;
         let file_name = file_name.as_str();
         let timestamp = timestamp.timestamp();
 
@@ -111,7 +176,8 @@ async fn handle_new_stream(path: &Path, file_name: String, file_size: i64) -> Re
         .await?;
 
         sqlx::query!(
-            "INSERT INTO streams(filename, filesize, ts, duration, preview_count, thumbnail_count, has_chat, datapoints_json, jumpcuts_json, inserted_at) values(?1, ?2, ?3, ?4, 0, 0, ?5, ?6, ?7, ?8)",
+            "INSERT INTO streams(library, filename, filesize, ts, duration, preview_count, thumbnail_count, has_chat, datapoints_json, jumpcuts_json, inserted_at) values(?1, ?2, ?3, ?4, ?5, 0, 0, ?6, ?7, ?8, ?9)",
+            library.name,
             file_name,
             file_size,
             timestamp,
@@ -162,6 +228,7 @@ async fn handle_new_stream(path: &Path, file_name: String, file_size: i64) -> Re
                     None => {
                         let game = Database::insert_possible_game(
                             state.tx.deref_mut(),
+                            None,
                             datapoint.game.clone(),
                             Some(datapoint.game),
                             None,
@@ -188,31 +255,25 @@ async fn handle_new_stream(path: &Path, file_name: String, file_size: i64) -> Re
             id: g.info.id,
             start_time: g.start_time,
         });
-    Database::replace_games(&mut tx, stream_id, games).await?;
+    Database::replace_games(&mut tx, None, stream_id, games).await?;
 
     Database::convert_twitch_progress(&mut tx, stream_id).await?;
 
     tx.commit().await?;
 
-    sender.send(Job::Thumbnails {
-        stream_id,
-        path: path.to_owned(),
-    })?;
-    sender.send(Job::Preview {
-        stream_id,
-        path: path.to_owned(),
-    })?;
-    sender.send(Job::Loudness { stream_id })?;
-    sender.send(Job::Chatspeed { stream_id })?;
+    queue_stream_jobs(stream_id, path).await?;
 
     update_cache().await?;
     Ok(())
 }
 
-async fn handle_modified_stream(path: &Path, file_name: String, file_size: i64) -> Result<()> {
+async fn handle_modified_stream(
+    library: &Library,
+    path: &Path,
+    file_name: String,
+    file_size: i64,
+) -> Result<()> {
     let db = DB.get().unwrap();
-    let sender = SENDER.get().unwrap();
-
     let timestamp = match parse_filename(path) {
         Some((date, _)) => date.timestamp(),
         None => {
@@ -230,14 +291,14 @@ async fn handle_modified_stream(path: &Path, file_name: String, file_size: i64)
 
     let stream_id = {
         let mut tx = db.pool.begin().await?;
-        let stream_id = Database::get_stream_id_by_filename(&mut tx, &file_name)
+        let stream_id = Database::get_stream_id_by_filename(&mut tx, &library.name, &file_name)
             .await
             .unwrap();
         let duration = duration.as_secs_f64();
 
-        // update filesize
+        // update filesize, the chat is indexed again with the new timestamp
         sqlx::query!(
-            "UPDATE streams SET filesize = ?1, ts = ?2, duration = ?3 WHERE id = ?4",
+            "UPDATE streams SET filesize = ?1, ts = ?2, duration = ?3, chat_indexed = 0 WHERE id = ?4",
             file_size,
             timestamp,
             duration,
@@ -247,22 +308,14 @@ async fn handle_modified_stream(path: &Path, file_name: String, file_size: i64)
         .await?;
 
         remove_thumbnails_and_preview(tx.deref_mut(), stream_id).await?;
+        remove_hls(tx.deref_mut(), stream_id).await?;
 
         tx.commit().await?;
 
         stream_id
     };
 
-    sender.send(Job::Thumbnails {
-        stream_id,
-        path: path.to_owned(),
-    })?;
-    sender.send(Job::Preview {
-        stream_id,
-        path: path.to_owned(),
-    })?;
-    sender.send(Job::Loudness { stream_id })?;
-    sender.send(Job::Chatspeed { stream_id })?;
+    queue_stream_jobs(stream_id, path).await?;
 
     update_cache().await?;
     Ok(())
@@ -275,19 +328,66 @@ enum ItemState {
     Modified,
     Removed,
 }
-pub async fn scan_streams() -> Result<()> {
+
+/// Held while (part of) a library is being scanned, so that a full rescan and the watcher don't
+/// handle the same file at the same time.
+static SCAN_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// Whether the given file name looks like a stream recording.
+pub fn is_stream_file(file_name: &str) -> bool {
+    // HACK
+    file_name.ends_with(".mp4") || file_name.ends_with(".mkv") || file_name.ends_with(".webm")
+}
+
+async fn handle_item(
+    library: &Library,
+    file_name: String,
+    file_size: u64,
+    state: ItemState,
+) -> Result<()> {
     let db = DB.get().unwrap();
+    let path = Path::new(&library.path).join(file_name.clone());
 
+    match state {
+        ItemState::Unchanged => {}
+        ItemState::New => {
+            println!("[{}] got new item: {}", library.name, file_name);
+            handle_new_stream(library, &path, file_name, file_size as i64).await?;
+        }
+        ItemState::Modified => {
+            println!("[{}] got updated item: {}", library.name, file_name);
+            handle_modified_stream(library, &path, file_name, file_size as i64).await?;
+        }
+        ItemState::Removed => {
+            println!("[{}] got removed item: {}", library.name, file_name);
+
+            let mut tx = db.pool.begin().await?;
+            let stream_id = Database::get_stream_id_by_filename(&mut tx, &library.name, &file_name)
+                .await
+                .unwrap();
+            remove_thumbnails_and_preview(tx.deref_mut(), stream_id).await?;
+            remove_hls(tx.deref_mut(), stream_id).await?;
+            Database::remove_stream(&mut tx, stream_id).await?;
+            tx.commit().await?;
+
+            update_cache().await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn scan_library(library: &Library) -> Result<bool> {
     let file_name_states = {
         let db_map: HashMap<String, u64> = {
-            Database::get_streams(get_conn().await?.borrow_mut())
+            Database::get_streams(get_conn().await?.borrow_mut(), Some(&library.name))
                 .await?
                 .into_iter()
                 .map(|stream| (stream.info.file_name.into(), stream.info.file_size))
                 .collect()
         };
         let dir_map: HashMap<String, u64> = {
-            let dir = read_dir(STREAMS_DIR).await?;
+            let dir = read_dir(&library.path).await?;
             ReadDirStream::new(dir)
                 .then(|item| async {
                     let item = item.unwrap();
@@ -303,11 +403,7 @@ pub async fn scan_streams() -> Result<()> {
         let mut m: HashMap<String, (u64, ItemState)> = HashMap::new();
 
         for dir_file in &dir_map {
-            // HACK
-            if !(dir_file.0.ends_with(".mp4")
-                || dir_file.0.ends_with(".mkv")
-                || dir_file.0.ends_with(".webm"))
-            {
+            if !is_stream_file(dir_file.0) {
                 continue;
             }
 
@@ -338,36 +434,22 @@ pub async fn scan_streams() -> Result<()> {
 
     let mut all_unchanged = true;
     for (file_name, (file_size, state)) in file_name_states {
-        let path = Path::new(STREAMS_DIR).join(file_name.clone());
-
-        match state {
-            ItemState::Unchanged => {}
-            ItemState::New => {
-                println!("got new item: {}", file_name);
-                all_unchanged = false;
-                handle_new_stream(&path, file_name, file_size as i64).await?;
-            }
-            ItemState::Modified => {
-                println!("got updated item: {}", file_name);
-                all_unchanged = false;
-
-                handle_modified_stream(&path, file_name, file_size as i64).await?;
-            }
-            ItemState::Removed => {
-                println!("got removed item: {}", file_name);
-                all_unchanged = false;
-
-                let mut tx = db.pool.begin().await?;
-                let stream_id = Database::get_stream_id_by_filename(&mut tx, &file_name)
-                    .await
-                    .unwrap();
-                remove_thumbnails_and_preview(tx.deref_mut(), stream_id).await?;
-                Database::remove_stream(&mut tx, stream_id).await?;
-                tx.commit().await?;
-
-                update_cache().await?;
-            }
+        if state != ItemState::Unchanged {
+            all_unchanged = false;
         }
+        handle_item(library, file_name, file_size, state).await?;
+    }
+
+    Ok(all_unchanged)
+}
+
+pub async fn scan_streams() -> Result<()> {
+    let config = CONFIG.get().unwrap();
+    let _guard = SCAN_LOCK.lock().await;
+
+    let mut all_unchanged = true;
+    for library in &config.libraries {
+        all_unchanged &= scan_library(library).await?;
     }
 
     if all_unchanged {
@@ -377,41 +459,141 @@ pub async fn scan_streams() -> Result<()> {
     Ok(())
 }
 
+/// Scan a single file in the given library, handling it as new, modified or removed just like
+/// `scan_streams` would.
+pub async fn scan_file(library: &Library, file_name: &str) -> Result<()> {
+    let _guard = SCAN_LOCK.lock().await;
+
+    let db_file_size = {
+        let mut conn = get_conn().await?;
+        match Database::get_stream_id_by_filename(&mut conn, &library.name, file_name).await {
+            None => None,
+            Some(stream_id) => Database::get_stream_by_id(&mut conn, stream_id)
+                .await?
+                .map(|s| s.info.file_size),
+        }
+    };
+    let dir_file_size = match metadata(Path::new(&library.path).join(file_name)).await {
+        Ok(m) => Some(m.len()),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+
+    let (file_size, state) = match (db_file_size, dir_file_size) {
+        (None, None) => return Ok(()),
+        (None, Some(dir_file_size)) => (dir_file_size, ItemState::New),
+        (Some(db_file_size), Some(dir_file_size)) if db_file_size == dir_file_size => {
+            (dir_file_size, ItemState::Unchanged)
+        }
+        (Some(_), Some(dir_file_size)) => (dir_file_size, ItemState::Modified),
+        (Some(db_file_size), None) => (db_file_size, ItemState::Removed),
+    };
+
+    handle_item(library, file_name.to_owned(), file_size, state).await
+}
+
+/// The hype model that was used for what is in the database, to notice when it is changed.
+const HYPE_MODEL_META_KEY: &str = "hype_model";
+
+/// If the hype model changed since the last run, recompute the hype average of every stream and
+/// have its highlights detected again.
+async fn apply_hype_model() -> Result<()> {
+    let model = &CONFIG.get().unwrap().hype;
+    let serialized = serde_json::to_string(model)?;
+
+    let mut conn = get_conn().await?;
+    if Database::get_meta(&mut conn, HYPE_MODEL_META_KEY)
+        .await?
+        .as_ref()
+        == Some(&serialized)
+    {
+        return Ok(());
+    }
+    println!("hype model changed, recomputing hype averages");
+
+    for s in Database::get_streams(&mut conn, None).await? {
+        let datapoints = Database::get_hype_datapoints(&mut conn, s.info.id, model).await?;
+        Database::set_hype_average(&mut conn, s.info.id, hype_average(&datapoints)).await?;
+    }
+    Database::reset_highlights_detected(&mut conn).await?;
+    Database::set_meta(&mut conn, HYPE_MODEL_META_KEY, &serialized).await?;
+
+    Ok(())
+}
+
 pub async fn generate_missing_info() -> Result<()> {
     let db = DB.get().unwrap();
+    let config = CONFIG.get().unwrap();
     let sender = SENDER.get().unwrap();
 
+    apply_hype_model().await?;
+
     let mut conn = db.pool.acquire().await?;
 
-    let streams = Database::get_streams(&mut conn).await?;
+    // Jobs that were queued before a restart are picked up again by the workers.
+    let pending = sender.pending_stream_ids().await?;
+    let without_highlights = Database::get_streams_without_highlights(&mut conn).await?;
+    let without_chat_index = Database::get_streams_without_chat_index(&mut conn).await?;
+
+    let streams = Database::get_streams(&mut conn, None).await?;
     for s in streams {
-        if s.info.has_preview {
+        if pending.contains(&s.info.id) {
             continue;
         }
 
         let stream_id = s.info.id;
-        let path = s.info.file_name.stream_path(STREAMS_DIR);
+        let path = s
+            .info
+            .file_name
+            .stream_path(config.library_dir(&s.info.library)?);
+
+        if config.generate_hls && s.info.hls_renditions.is_empty() {
+            println!("[{}] no hls renditions in database, generating", stream_id);
+            sender
+                .send(Job::Hls {
+                    stream_id,
+                    path: path.clone(),
+                })
+                .await?;
+        }
+
+        if without_chat_index.contains(&stream_id) {
+            println!("[{}] chat is not in the search index, indexing", stream_id);
+            sender.send(Job::ChatIndex { stream_id }).await?;
+        }
+
+        if s.info.has_chat && needs_seek_index(&s.info).await? {
+            println!("[{}] chat has no seek index, building", stream_id);
+            sender.send(Job::ChatSeekIndex { stream_id }).await?;
+        }
+
+        if s.info.has_preview {
+            if s.info.scrub_thumbnails_vtt.is_none() {
+                println!("[{}] no scrub sprites in database, generating", stream_id);
+                sender.send(Job::ScrubSprites { stream_id, path }).await?;
+            }
+            // New streams get this once their hype is known.
+            if without_highlights.contains(&stream_id) {
+                println!("[{}] highlights were never detected, detecting", stream_id);
+                sender.send(Job::Highlights { stream_id }).await?;
+            }
+            continue;
+        }
 
         println!("[{}] no preview in database, generating info", stream_id);
 
         remove_thumbnails_and_preview(&db.pool, stream_id).await?;
 
-        sender.send(Job::Thumbnails {
-            stream_id,
-            path: path.clone(),
-        })?;
-
-        if [1170, 1174].contains(&stream_id) {
-            println!(
-                "[{}] while not having preview, this stream is blacklisted for preview generation. Skipping preview generation",
-                stream_id
-            );
-        } else {
-            sender.send(Job::Preview { stream_id, path })?;
-        }
+        sender
+            .send(Job::Thumbnails {
+                stream_id,
+                path: path.clone(),
+            })
+            .await?;
 
-        sender.send(Job::Loudness { stream_id })?;
-        sender.send(Job::Chatspeed { stream_id })?;
+        sender.send(Job::Preview { stream_id, path }).await?;
+        sender.send(Job::Loudness { stream_id }).await?;
+        sender.send(Job::Chatspeed { stream_id }).await?;
     }
 
     Ok(())
@@ -1,8 +1,10 @@
-use crate::STREAMS_DIR;
+use crate::CONFIG;
 
 use chrono::{DateTime, Duration, Utc};
 use tokio::process::Command;
 
+use streamwatch_shared::functions::run_command;
+
 use itertools::Itertools;
 
 use anyhow::Result;
@@ -10,10 +12,12 @@ use anyhow::Result;
 use streamwatch_shared::types::StreamInfo;
 
 async fn _get_volume_points(stream: &StreamInfo) -> Result<Vec<(f32, f32)>> {
+    let streams_dir = CONFIG.get().unwrap().library_dir(&stream.library)?;
+
     let mut cmd = {
         let mut cmd = Command::new("nice");
         cmd.args(&["-n10", "ffmpeg", "-i"]);
-        cmd.arg(stream.file_name.stream_path(STREAMS_DIR));
+        cmd.arg(stream.file_name.stream_path(streams_dir));
         cmd.args(&[
             "-vn",
             "-af",
@@ -26,7 +30,7 @@ async fn _get_volume_points(stream: &StreamInfo) -> Result<Vec<(f32, f32)>> {
     };
 
     let output = {
-        let output = cmd.output().await?;
+        let output = run_command(&mut cmd).await?;
         String::from_utf8(output.stdout)?
     };
 
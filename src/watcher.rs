@@ -0,0 +1,142 @@
+use crate::config::Library;
+use crate::scan::{is_stream_file, scan_file, scan_streams};
+use crate::CONFIG;
+
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use tokio::fs::metadata;
+use tokio::sync::mpsc;
+
+use notify::{EventKind, RecursiveMode, Watcher};
+
+use anyhow::Result;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+struct PendingFile {
+    last_change: Instant,
+    /// The size of the file when we last looked at it, `None` if it didn't exist.
+    last_size: Option<u64>,
+}
+
+async fn file_size(path: &Path) -> Result<Option<u64>> {
+    match metadata(path).await {
+        Ok(m) => Ok(Some(m.len())),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Returns the index of the library `path` is directly in and the file name, if `path` is a
+/// stream file in one of the libraries.
+fn library_file(libraries: &[Library], path: &Path) -> Option<(usize, String)> {
+    let file_name = path.file_name()?.to_str()?;
+    if !is_stream_file(file_name) {
+        return None;
+    }
+
+    let parent = path.parent()?;
+    let index = libraries
+        .iter()
+        .position(|library| Path::new(&library.path) == parent)?;
+
+    Some((index, file_name.to_owned()))
+}
+
+/// Watch all libraries for changes. A changed file is scanned once it hasn't been touched for
+/// `watch_debounce_secs` and its size is the same as when we last saw it, so recordings that are
+/// still being written are only picked up when they are done.
+pub async fn watch_libraries() -> Result<()> {
+    let config = CONFIG.get().unwrap();
+    let debounce = Duration::from_secs(config.watch_debounce_secs);
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |res| {
+        // The receiver only goes away when we stop watching, so we don't care about errors.
+        let _ = tx.send(res);
+    })?;
+    for library in &config.libraries {
+        watcher.watch(Path::new(&library.path), RecursiveMode::NonRecursive)?;
+        println!("[{}] watching {} for changes", library.name, library.path);
+    }
+
+    // Catch up on everything that changed while we weren't watching.
+    scan_streams().await?;
+
+    let mut pending: HashMap<(usize, String), PendingFile> = HashMap::new();
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+
+    loop {
+        tokio::select! {
+            res = rx.recv() => {
+                let event: notify::Event = match res {
+                    None => break,
+                    Some(Err(e)) => {
+                        eprintln!("error from library watcher: {}", e);
+                        continue;
+                    }
+                    Some(Ok(event)) => event,
+                };
+                if matches!(event.kind, EventKind::Access(_)) {
+                    continue;
+                }
+
+                for path in event.paths {
+                    let key = match library_file(&config.libraries, &path) {
+                        None => continue,
+                        Some(key) => key,
+                    };
+
+                    match pending.get_mut(&key) {
+                        Some(file) => file.last_change = Instant::now(),
+                        None => {
+                            let last_size = file_size(&path).await.unwrap_or(None);
+                            pending.insert(key, PendingFile {
+                                last_change: Instant::now(),
+                                last_size,
+                            });
+                        }
+                    }
+                }
+            }
+            _ = interval.tick() => {
+                let ready: Vec<_> = pending
+                    .iter()
+                    .filter(|(_, file)| file.last_change.elapsed() >= debounce)
+                    .map(|(key, _)| key.clone())
+                    .collect();
+
+                for key in ready {
+                    let library = &config.libraries[key.0];
+                    let file_name = &key.1;
+
+                    let size = match file_size(&Path::new(&library.path).join(file_name)).await {
+                        Ok(size) => size,
+                        Err(e) => {
+                            eprintln!("[{}] error checking {}: {:?}", library.name, file_name, e);
+                            continue;
+                        }
+                    };
+
+                    let file = pending.get_mut(&key).unwrap();
+                    if file.last_size != size {
+                        // still being written to, check again later
+                        file.last_size = size;
+                        file.last_change = Instant::now();
+                        continue;
+                    }
+                    pending.remove(&key);
+
+                    if let Err(e) = scan_file(library, file_name).await {
+                        eprintln!("[{}] error scanning {}: {:?}", library.name, file_name, e);
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -1,24 +1,34 @@
-use crate::chat::handle_chat_request;
+use crate::chat::{chat_replay_ws, handle_chat_request};
 use crate::db::Database;
-use crate::job_handler::{Job, SENDER};
+use crate::hype::{
+    cache_downsampled, downsample, get_cached_downsampled, Downsampling, HypeModelOverride,
+    Resolution,
+};
+use crate::job_handler::{get_jobs_overview, Job, SENDER};
+use crate::remux::remux_to_fmp4;
 use crate::scan::scan_streams;
 use crate::util::AnyhowError;
 use crate::watchparty::{get_watch_parties, watch_party_ws};
-use crate::{check, conn, function, get_conn, DB, STREAMS_DIR, STREAMS_JSON_CACHE};
+use crate::{check, conn, function, get_conn, CONFIG, DB, STREAMS_JSON_CACHE};
 
-use chrono::Utc;
+use chrono::{DateTime, Duration, Utc};
 use futures::TryStreamExt;
-use serde_json::value::RawValue;
+use serde_json::value::{to_raw_value, RawValue};
+use sqlx::SqliteConnection;
 use streamwatch_shared::types::{
-    Clip, ConversionProgress, CreateClipRequest, GameItem, StreamJson, StreamProgress,
+    Clip, ConversionProgress, CreateClipRequest, ExportStatus, GameItem, PostMessageRequest, Role,
+    StreamJson, StreamProgress, User,
 };
 
 use std::collections::{HashMap, HashSet};
-use std::net::IpAddr;
 use std::ops::{Deref, DerefMut};
-use std::str::FromStr;
+use std::time::Duration as StdDuration;
+
+use tokio_util::io::ReaderStream;
 
-use warp::http::StatusCode;
+use warp::http::{HeaderValue, StatusCode};
+use warp::hyper::Body;
+use warp::reject::Reject;
 use warp::{Filter, Reply};
 
 use anyhow::anyhow;
@@ -35,26 +45,77 @@ macro_rules! reply_status {
     };
 }
 
-macro_rules! check_username_password {
-    ($conn:expr, $username:expr, $password:expr, $on_err:expr) => {{
-        let user_id = match check!(Database::get_userid_by_username($conn, $username).await) {
-            None => return $on_err,
-            Some(id) => id,
+macro_rules! log_api_call {
+    ($conn:expr, $user:expr) => {
+        let _ = Database::insert_api_call($conn, &$user.username, function!()).await;
+    };
+}
+
+const SESSION_COOKIE: &str = "session";
+
+#[derive(Debug)]
+struct Unauthorized;
+impl Reject for Unauthorized {}
+
+#[derive(Debug)]
+struct Forbidden;
+impl Reject for Forbidden {}
+
+/// The session token from the `Authorization: Bearer` header or the session cookie.
+fn session_token() -> impl Filter<Extract = (Option<String>,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::cookie::optional::<String>(SESSION_COOKIE))
+        .map(|header: Option<String>, cookie: Option<String>| {
+            header
+                .and_then(|h| h.strip_prefix("Bearer ").map(str::to_owned))
+                .or(cookie)
+        })
+}
+
+/// Resolves the logged in user from the session token, if there is a valid one.
+fn optional_user() -> impl Filter<Extract = (Option<User>,), Error = warp::Rejection> + Clone {
+    session_token().and_then(|token: Option<String>| async move {
+        let user = match token {
+            None => None,
+            Some(token) => check!(Database::get_session_user(conn!(), &token).await),
         };
+        Ok::<_, warp::Rejection>(user)
+    })
+}
 
-        if !check!(Database::check_password($conn, user_id, $password).await) {
-            return $on_err;
-        }
+/// Resolves the logged in user from the session token, rejecting the request with
+/// `401 Unauthorized` if there is none.
+fn user() -> impl Filter<Extract = (User,), Error = warp::Rejection> + Clone {
+    optional_user().and_then(|user: Option<User>| async move {
+        user.ok_or_else(|| warp::reject::custom(Unauthorized))
+    })
+}
 
-        let _ = Database::insert_api_call($conn, $username, function!()).await;
+/// Resolves the logged in user like [`user`], additionally rejecting the request with
+/// `403 Forbidden` if the user doesn't have at least the given role.
+fn require_role(role: Role) -> impl Filter<Extract = (User,), Error = warp::Rejection> + Clone {
+    user().and_then(move |user: User| async move {
+        if user.role >= role {
+            Ok(user)
+        } else {
+            Err(warp::reject::custom(Forbidden))
+        }
+    })
+}
 
-        user_id
-    }};
+async fn handle_rejection(err: warp::Rejection) -> Result<warp::reply::Response, warp::Rejection> {
+    if err.find::<Unauthorized>().is_some() {
+        Ok(reply_status!(StatusCode::UNAUTHORIZED))
+    } else if err.find::<Forbidden>().is_some() {
+        Ok(reply_status!(StatusCode::FORBIDDEN))
+    } else {
+        Err(err)
+    }
 }
 
 async fn _get_clips(
     stream_id: Option<i64>,
-    hashes: HashMap<String, String>,
+    user: Option<User>,
 ) -> Result<warp::reply::Json, warp::Rejection> {
     #[derive(Serialize)]
     struct ClipJson {
@@ -66,18 +127,10 @@ async fn _get_clips(
 
     let mut conn = get_conn!();
 
-    let username = hashes.get("username").cloned().unwrap_or(String::new());
-    let user_pass: Option<(String, String)> = if !username.is_empty() {
-        let password = hashes.get("password").cloned().unwrap_or(String::new());
-        Some((username, password))
-    } else {
-        None
-    };
-
     let (viewed_set, stream_progress): (HashSet<i64>, HashMap<i64, StreamProgress>) =
-        if let Some((username, password)) = user_pass {
-            let user_id =
-                check_username_password!(&mut conn, &username, &password, Err(warp::reject()));
+        if let Some(user) = user {
+            log_api_call!(&mut conn, user);
+            let user_id = user.id;
 
             let viewed_set = check!(
                 sqlx::query!(
@@ -112,11 +165,15 @@ async fn _get_clips(
 }
 
 #[derive(Clone, Debug, Deserialize)]
-pub struct PasswordQuery {
-    password: String,
+struct StreamsQuery {
+    library: Option<String>,
 }
+async fn streams(query: StreamsQuery) -> Result<warp::reply::Json, warp::Rejection> {
+    if let Some(library) = query.library {
+        let streams = check!(Database::get_streams(conn!(), Some(&library)).await);
+        return Ok(warp::reply::json(&streams));
+    }
 
-async fn streams() -> Result<warp::reply::Json, warp::Rejection> {
     let cache = STREAMS_JSON_CACHE.get().unwrap();
     let streams = cache.read().await;
     Ok(warp::reply::json(streams.deref()))
@@ -127,85 +184,277 @@ async fn processing_streams() -> Result<warp::reply::Json, warp::Rejection> {
     Ok(warp::reply::json(&streams))
 }
 
+async fn get_jobs(user: User) -> Result<warp::reply::Json, warp::Rejection> {
+    log_api_call!(conn!(), user);
+
+    let overview = check!(get_jobs_overview().await);
+    Ok(warp::reply::json(&overview))
+}
+
+async fn retry_job(job_id: i64, user: User) -> Result<warp::reply::Response, warp::Rejection> {
+    log_api_call!(conn!(), user);
+
+    if check!(SENDER.get().unwrap().retry(job_id).await) {
+        Ok(warp::reply().into_response())
+    } else {
+        Ok(reply_status!(StatusCode::CONFLICT))
+    }
+}
+
+async fn cancel_job(job_id: i64, user: User) -> Result<warp::reply::Response, warp::Rejection> {
+    log_api_call!(conn!(), user);
+
+    if check!(SENDER.get().unwrap().cancel(job_id).await) {
+        Ok(warp::reply().into_response())
+    } else {
+        Ok(reply_status!(StatusCode::CONFLICT))
+    }
+}
+
+async fn get_processing_overrides(user: User) -> Result<warp::reply::Json, warp::Rejection> {
+    let mut conn = get_conn!();
+    log_api_call!(&mut conn, user);
+
+    let overrides = check!(Database::get_processing_overrides(&mut conn).await);
+    Ok(warp::reply::json(&overrides))
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct ProcessingOverrideBody {
+    reason: Option<String>,
+}
+async fn set_processing_override(
+    stream_id: i64,
+    kind: String,
+    user: User,
+    body: ProcessingOverrideBody,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    if !Job::KINDS.contains(&kind.as_str()) {
+        return Ok(reply_status!(StatusCode::BAD_REQUEST));
+    }
+
+    let mut conn = get_conn!();
+    log_api_call!(&mut conn, user);
+
+    check!(
+        Database::set_processing_override(&mut conn, user.id, stream_id, &kind, body.reason).await
+    );
+    Ok(warp::reply().into_response())
+}
+
+async fn remove_processing_override(
+    stream_id: i64,
+    kind: String,
+    user: User,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let mut conn = get_conn!();
+    log_api_call!(&mut conn, user);
+
+    if check!(Database::remove_processing_override(&mut conn, stream_id, &kind).await) {
+        Ok(warp::reply().into_response())
+    } else {
+        Ok(reply_status!(StatusCode::NOT_FOUND))
+    }
+}
+
 async fn replace_games(
     stream_id: i64,
+    user: User,
     items: Vec<GameItem>,
 ) -> Result<warp::reply::Response, warp::Rejection> {
-    check!(Database::replace_games(conn!(), stream_id, items).await);
+    let mut conn = get_conn!();
+    log_api_call!(&mut conn, user);
+
+    check!(Database::replace_games(&mut conn, Some(user.id), stream_id, items).await);
     Ok(warp::reply().into_response())
 }
 
 async fn replace_persons(
     stream_id: i64,
+    user: User,
     person_ids: Vec<i64>,
 ) -> Result<warp::reply::Response, warp::Rejection> {
-    check!(Database::replace_persons(conn!(), stream_id, person_ids).await);
+    let mut conn = get_conn!();
+    log_api_call!(&mut conn, user);
+
+    check!(Database::replace_persons(&mut conn, user.id, stream_id, person_ids).await);
     Ok(warp::reply().into_response())
 }
 
-async fn get_stream_hype(stream_id: i64) -> Result<warp::reply::Json, warp::Rejection> {
-    let datapoints = check!(Database::get_hype_datapoints(conn!(), stream_id).await);
-    Ok(warp::reply::json(&datapoints))
+#[derive(Clone, Debug, Deserialize)]
+struct PlayQuery {
+    /// Where to start playing, in seconds.
+    start: Option<f64>,
+}
+async fn play_stream_mp4(
+    stream_id: i64,
+    _user: User,
+    query: PlayQuery,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let stream = match check!(Database::get_stream_by_id(conn!(), stream_id).await) {
+        None => return Ok(reply_status!(StatusCode::NOT_FOUND)),
+        Some(s) => s,
+    };
+
+    let start = query.start.unwrap_or(0.0);
+    if !start.is_finite() || start < 0.0 || start > stream.info.duration.as_secs_f64() {
+        return Ok(reply_status!(StatusCode::BAD_REQUEST));
+    }
+
+    let config = CONFIG.get().unwrap();
+    let path = stream
+        .info
+        .file_name
+        .stream_path(check!(config.library_dir(&stream.info.library)));
+    let body = check!(remux_to_fmp4(&path, StdDuration::from_secs_f64(start)).await);
+
+    let mut res = warp::reply::Response::new(Body::wrap_stream(body));
+    let headers = res.headers_mut();
+    headers.insert("content-type", HeaderValue::from_static("video/mp4"));
+    // The output is generated as it is sent, seeking is done by requesting a different start.
+    headers.insert("accept-ranges", HeaderValue::from_static("none"));
+    Ok(res)
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HypeQuery {
+    /// Seconds per bucket.
+    resolution: Option<i64>,
+    /// Number of buckets over the whole stream.
+    buckets: Option<usize>,
+    #[serde(default)]
+    method: Downsampling,
+    #[serde(flatten)]
+    model: HypeModelOverride,
+}
+
+/// With `resolution` or `buckets`, the hype is downsampled into columns. The other query
+/// parameters override parts of the configured hype model, to try out other weightings.
+async fn get_stream_hype(
+    stream_id: i64,
+    query: HypeQuery,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let configured = query.model == HypeModelOverride::default();
+    let model = query.model.apply(&CONFIG.get().unwrap().hype);
+    if let Err(e) = model.validate() {
+        return Ok(reply_status!(
+            warp::reply::json(&e.to_string()),
+            StatusCode::BAD_REQUEST
+        ));
+    }
+
+    let resolution = match (query.resolution, query.buckets) {
+        (None, None) => None,
+        (Some(secs), None) if secs > 0 => Some(Resolution::Seconds(secs)),
+        (None, Some(n)) if n > 0 => Some(Resolution::Buckets(n)),
+        _ => {
+            return Ok(reply_status!(
+                warp::reply::json(&"expected either a positive resolution or number of buckets"),
+                StatusCode::BAD_REQUEST
+            ))
+        }
+    };
+    let method = query.method;
+
+    if let Some(resolution) = resolution.filter(|_| configured) {
+        if let Some(json) = get_cached_downsampled(stream_id, resolution, method).await {
+            return Ok(warp::reply::json(&json).into_response());
+        }
+    }
+
+    let datapoints = check!(Database::get_hype_datapoints(conn!(), stream_id, &model).await);
+    let resolution = match resolution {
+        None => return Ok(warp::reply::json(&datapoints).into_response()),
+        Some(resolution) => resolution,
+    };
+
+    let json = check!(to_raw_value(&downsample(&datapoints, resolution, method)));
+    if configured {
+        cache_downsampled(stream_id, resolution, method, json.clone()).await;
+    }
+    Ok(warp::reply::json(&json).into_response())
 }
 
 async fn get_stream_clips(
     stream_id: i64,
-    hashes: HashMap<String, String>,
+    user: Option<User>,
 ) -> Result<warp::reply::Json, warp::Rejection> {
-    _get_clips(Some(stream_id), hashes).await
+    _get_clips(Some(stream_id), user).await
 }
 
-async fn get_stream_ratings(
-    username: String,
-    password: PasswordQuery,
-) -> Result<warp::reply::Response, warp::Rejection> {
+async fn get_stream_ratings(user: User) -> Result<warp::reply::Response, warp::Rejection> {
     let mut conn = get_conn!();
+    log_api_call!(&mut conn, user);
 
-    let user_id = check_username_password!(
-        &mut conn,
-        &username,
-        &password.password,
-        Ok(reply_status!(StatusCode::UNAUTHORIZED))
-    );
-
-    let map = check!(Database::get_ratings(&mut conn, user_id).await);
+    let map = check!(Database::get_ratings(&mut conn, user.id).await);
 
     Ok(reply_status!(warp::reply::json(&map), StatusCode::FOUND))
 }
 
 #[derive(Clone, Debug, Deserialize)]
 struct RateStreamBody {
-    pub username: String,
     pub score: i8,
 }
 async fn rate_stream(
     stream_id: i64,
-    password: PasswordQuery,
-    RateStreamBody { username, score }: RateStreamBody,
+    user: User,
+    RateStreamBody { score }: RateStreamBody,
 ) -> Result<warp::reply::Response, warp::Rejection> {
     if ![-1, 0, 1].contains(&score) {
         return Ok(reply_status!(StatusCode::BAD_REQUEST));
     }
 
     let mut conn = get_conn!();
+    log_api_call!(&mut conn, user);
 
-    let user_id = check_username_password!(
-        &mut conn,
-        &username,
-        &password.password,
-        Ok(reply_status!(StatusCode::UNAUTHORIZED))
-    );
-
-    check!(Database::set_stream_rating(&mut conn, stream_id, user_id, score).await);
+    check!(Database::set_stream_rating(&mut conn, stream_id, user.id, score).await);
 
     Ok(warp::reply().into_response())
 }
 
+/// Twitch's limit.
+const MAX_MESSAGE_LENGTH: usize = 500;
+/// Post a chat message at a point in the VOD, it shows up in the chat replay from then on.
+async fn post_message(
+    stream_id: i64,
+    user: User,
+    request: PostMessageRequest,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let mut conn = get_conn!();
+    log_api_call!(&mut conn, user);
+
+    let stream = match check!(Database::get_stream_by_id(&mut conn, stream_id).await) {
+        None => return Ok(reply_status!(StatusCode::NOT_FOUND)),
+        Some(s) => s,
+    };
+
+    let message = request.message.trim();
+    if message.is_empty()
+        || message.chars().count() > MAX_MESSAGE_LENGTH
+        || request.offset > stream.info.duration
+    {
+        return Ok(reply_status!(StatusCode::BAD_REQUEST));
+    }
+
+    let time = stream.info.timestamp + check!(Duration::from_std(request.offset));
+    let message =
+        check!(Database::add_message(&mut conn, stream_id, &user, time, message.to_owned()).await);
+    Ok(reply_status!(
+        warp::reply::json(&message),
+        StatusCode::CREATED
+    ))
+}
+
 async fn set_custom_title(
     stream_id: i64,
+    user: User,
     title: String,
 ) -> Result<warp::reply::Response, warp::Rejection> {
-    check!(Database::set_custom_stream_title(conn!(), stream_id, title).await);
+    let mut conn = get_conn!();
+    log_api_call!(&mut conn, user);
+
+    check!(Database::set_custom_stream_title(&mut conn, user.id, stream_id, title).await);
     Ok(warp::reply().into_response())
 }
 
@@ -229,42 +478,26 @@ async fn get_stream_other_progress(stream_id: i64) -> Result<warp::reply::Json,
     Ok(warp::reply::json(&other_progress))
 }
 
-async fn get_streams_progress(
-    username: String,
-    password: PasswordQuery,
-) -> Result<warp::reply::Response, warp::Rejection> {
+async fn get_streams_progress(user: User) -> Result<warp::reply::Response, warp::Rejection> {
     let mut conn = get_conn!();
+    log_api_call!(&mut conn, user);
 
-    let user_id = check_username_password!(
-        &mut conn,
-        &username,
-        &password.password,
-        Ok(reply_status!(StatusCode::UNAUTHORIZED))
-    );
-
-    let map = check!(Database::get_streams_progress(&mut conn, user_id).await);
+    let map = check!(Database::get_streams_progress(&mut conn, user.id).await);
 
     Ok(reply_status!(warp::reply::json(&map), StatusCode::FOUND))
 }
 async fn set_streams_progress(
-    username: String,
-    password: PasswordQuery,
+    user: User,
     progress: HashMap<i64, f64>,
 ) -> Result<warp::reply::Response, warp::Rejection> {
-    if username == "admin" {
+    if user.username == "admin" {
         return Ok(warp::reply().into_response());
     }
 
     let mut conn = get_conn!();
+    log_api_call!(&mut conn, user);
 
-    let user_id = check_username_password!(
-        &mut conn,
-        &username,
-        &password.password,
-        Ok(reply_status!(StatusCode::UNAUTHORIZED))
-    );
-
-    check!(Database::update_streams_progress(&mut conn, user_id, progress, Utc::now()).await);
+    check!(Database::update_streams_progress(&mut conn, user.id, progress, Utc::now()).await);
 
     Ok(warp::reply().into_response())
 }
@@ -275,8 +508,12 @@ async fn get_possible_games() -> Result<warp::reply::Json, warp::Rejection> {
 }
 
 async fn add_possible_game(
+    user: User,
     info: HashMap<String, String>,
 ) -> Result<warp::reply::Json, warp::Rejection> {
+    let mut conn = get_conn!();
+    log_api_call!(&mut conn, user);
+
     let game_info = {
         let name = check!(info.get("name").ok_or_else(|| anyhow!("name required")));
         let twitch_name = info.get("twitchName");
@@ -284,7 +521,8 @@ async fn add_possible_game(
 
         check!(
             Database::insert_possible_game(
-                conn!(),
+                &mut conn,
+                Some(user.id),
                 name.to_owned(),
                 twitch_name.cloned(),
                 platform.cloned()
@@ -301,101 +539,82 @@ async fn get_possible_persons() -> Result<warp::reply::Json, warp::Rejection> {
     Ok(warp::reply::json(&possible_persons))
 }
 
-async fn rescan_streams() -> Result<impl warp::Reply, warp::Rejection> {
+async fn rescan_streams(user: User) -> Result<impl warp::Reply, warp::Rejection> {
+    log_api_call!(conn!(), user);
+
     check!(scan_streams().await);
     Ok("scanned streams")
 }
 
-async fn get_all_clips(
-    hashes: HashMap<String, String>,
-) -> Result<warp::reply::Json, warp::Rejection> {
-    _get_clips(None, hashes).await
+async fn get_all_clips(user: Option<User>) -> Result<warp::reply::Json, warp::Rejection> {
+    _get_clips(None, user).await
 }
 
-#[derive(Clone, Debug, Deserialize)]
-struct ClipViewParams {
-    pub username: String,
-    pub password: String,
-}
 async fn add_clip_view(
     clip_id: i64,
-    params: ClipViewParams,
+    user: Option<User>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     let mut conn = get_conn!();
 
-    let user_id: Option<i64> = match params.username.as_str() {
-        "" => None,
-        username => match check!(Database::get_userid_by_username(&mut conn, username).await) {
-            None => return Err(warp::reject()),
-            Some(id) => Some(id),
-        },
-    };
-    let password_match = match user_id {
-        None => true,
-        Some(user_id) => {
-            check!(Database::check_password(&mut conn, user_id, &params.password).await)
-        }
-    };
-    if !password_match {
-        return Err(warp::reject());
-    }
-
-    if user_id.is_some() {
-        let _ = Database::insert_api_call(&mut conn, &params.username, function!()).await;
+    if let Some(user) = &user {
+        log_api_call!(&mut conn, user);
     }
+    let user_id = user.map(|u| u.id);
     check!(Database::add_clip_view(&mut conn, clip_id, user_id).await);
 
     Ok(warp::reply::with_status(warp::reply(), StatusCode::CREATED))
 }
 
 async fn create_clip(
-    password: PasswordQuery,
+    user: User,
     clip_request: CreateClipRequest,
 ) -> Result<warp::reply::Json, warp::Rejection> {
     let mut conn = get_conn!();
+    log_api_call!(&mut conn, user);
 
-    let n: Option<String> = None; // HACK
-    let user_id = check_username_password!(
-        &mut conn,
-        &clip_request.author_username,
-        &password.password,
-        Ok(warp::reply::json(&n))
-    );
-
-    let clip = check!(Database::create_clip(&mut conn, user_id, clip_request).await);
-
-    {
-        let sender = SENDER.get().unwrap();
-        sender.send(Job::ClipPreview { clip_id: clip.id }).unwrap();
-        sender
-            .send(Job::ClipThumbnail { clip_id: clip.id })
-            .unwrap();
-    }
+    let clip = check!(Database::create_clip(&mut conn, &user, clip_request).await);
+    check!(queue_clip_jobs(clip.id).await);
 
     Ok(warp::reply::json(&clip))
 }
 
+/// Make the preview and thumbnail of a new or changed clip.
+async fn queue_clip_jobs(clip_id: i64) -> anyhow::Result<()> {
+    let sender = SENDER.get().unwrap();
+    sender.send(Job::ClipPreview { clip_id }).await?;
+    sender.send(Job::ClipThumbnail { clip_id }).await?;
+    Ok(())
+}
+
 async fn update_clip(
     clip_id: i64,
-    password: PasswordQuery,
+    user: User,
     clip_request: CreateClipRequest,
 ) -> Result<warp::reply::Json, warp::Rejection> {
     let mut conn = get_conn!();
+    log_api_call!(&mut conn, user);
 
     let n: Option<String> = None; // HACK
-    let user_id = check_username_password!(
-        &mut conn,
-        &clip_request.author_username,
-        &password.password,
-        Ok(warp::reply::json(&n))
-    );
-
-    let updated = check!(Database::update_clip(&mut conn, user_id, clip_id, clip_request).await);
+    let old = check!(Database::get_clip_by_id(&mut conn, clip_id).await);
+    let updated = check!(Database::update_clip(&mut conn, user.id, clip_id, clip_request).await);
     if updated {
+        check!(queue_clip_jobs(clip_id).await);
+
+        // The export is outdated now, make a new one if there was one.
+        if let Some(old) =
+            old.filter(|c| matches!(c.export_status, ExportStatus::Ready | ExportStatus::Pending))
         {
-            let sender = SENDER.get().unwrap();
-            sender.send(Job::ClipPreview { clip_id }).unwrap();
-            sender.send(Job::ClipThumbnail { clip_id }).unwrap();
+            check!(
+                SENDER
+                    .get()
+                    .unwrap()
+                    .send(Job::ClipExport {
+                        clip_id,
+                        burn_title: old.export_has_title,
+                        burn_chat: old.export_has_chat,
+                    })
+                    .await
+            );
         }
 
         Ok(warp::reply::json(&n))
@@ -404,18 +623,153 @@ async fn update_clip(
     }
 }
 
-async fn add_twitch_progress(
-    username: String,
-    password: PasswordQuery,
-) -> Result<impl warp::Reply, warp::Rejection> {
+#[derive(Clone, Debug, Deserialize)]
+struct ExportClipBody {
+    #[serde(default)]
+    pub burn_title: bool,
+    #[serde(default)]
+    pub burn_chat: bool,
+}
+async fn export_clip(
+    clip_id: i64,
+    user: User,
+    ExportClipBody {
+        burn_title,
+        burn_chat,
+    }: ExportClipBody,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let mut conn = get_conn!();
+    log_api_call!(&mut conn, user);
+
+    let clip = match check!(Database::get_clip_by_id(&mut conn, clip_id).await) {
+        None => return Ok(reply_status!(StatusCode::NOT_FOUND)),
+        Some(c) => c,
+    };
+
+    let has_chat = match check!(Database::get_stream_by_id(&mut conn, clip.stream_id).await) {
+        None => false,
+        Some(stream) => stream.info.has_chat,
+    };
+
+    let burn_title = burn_title && clip.title.is_some();
+    let burn_chat = burn_chat && has_chat;
+    let job = Job::ClipExport {
+        clip_id,
+        burn_title,
+        burn_chat,
+    };
+
+    let sender = SENDER.get().unwrap();
+    let up_to_date = match clip.export_status {
+        ExportStatus::Ready => {
+            clip.export_has_title == burn_title && clip.export_has_chat == burn_chat
+        }
+        // A different export replaces the pending one.
+        ExportStatus::Pending => check!(sender.is_pending(&job).await),
+        ExportStatus::None | ExportStatus::Failed => false,
+    };
+    if up_to_date {
+        return Ok(reply_status!(
+            warp::reply::json(&clip.export_status),
+            StatusCode::OK
+        ));
+    }
+
+    check!(sender.send(job).await);
+
+    Ok(reply_status!(
+        warp::reply::json(&ExportStatus::Pending),
+        StatusCode::ACCEPTED
+    ))
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct ChatSearchQuery {
+    q: String,
+    /// Only search the chat of this stream.
+    stream: Option<i64>,
+    limit: Option<i64>,
+    skip: Option<i64>,
+}
+const CHAT_SEARCH_DEFAULT_LIMIT: i64 = 50;
+const CHAT_SEARCH_MAX_LIMIT: i64 = 500;
+async fn search_chat(query: ChatSearchQuery) -> Result<warp::reply::Response, warp::Rejection> {
+    let phrase = query.q.trim();
+    let limit = query.limit.unwrap_or(CHAT_SEARCH_DEFAULT_LIMIT);
+    let skip = query.skip.unwrap_or(0);
+    if phrase.is_empty() || !(1..=CHAT_SEARCH_MAX_LIMIT).contains(&limit) || skip < 0 {
+        return Ok(reply_status!(StatusCode::BAD_REQUEST));
+    }
+
+    let hits = check!(Database::search_chat(conn!(), phrase, query.stream, limit, skip).await);
+    Ok(warp::reply::json(&hits).into_response())
+}
+
+async fn get_clip_suggestions(stream_id: i64) -> Result<warp::reply::Json, warp::Rejection> {
+    let suggestions = check!(Database::get_clip_suggestions(conn!(), Some(stream_id)).await);
+    Ok(warp::reply::json(&suggestions))
+}
+
+async fn accept_clip_suggestion(
+    suggestion_id: i64,
+    user: User,
+) -> Result<warp::reply::Response, warp::Rejection> {
     let mut conn = get_conn!();
-    let user_id = check_username_password!(
-        &mut conn,
-        &username,
-        &password.password,
-        Err(warp::reject())
+    log_api_call!(&mut conn, user);
+
+    let clip = check!(Database::accept_clip_suggestion(&mut conn, &user, suggestion_id).await);
+    match clip {
+        None => Ok(reply_status!(StatusCode::NOT_FOUND)),
+        Some(clip) => {
+            check!(queue_clip_jobs(clip.id).await);
+            Ok(reply_status!(warp::reply::json(&clip), StatusCode::CREATED))
+        }
+    }
+}
+
+async fn dismiss_clip_suggestion(
+    suggestion_id: i64,
+    user: User,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let mut conn = get_conn!();
+    log_api_call!(&mut conn, user);
+
+    if check!(Database::dismiss_clip_suggestion(&mut conn, suggestion_id).await) {
+        Ok(reply_status!(StatusCode::NO_CONTENT))
+    } else {
+        Ok(reply_status!(StatusCode::NOT_FOUND))
+    }
+}
+
+async fn download_clip(clip_id: i64) -> Result<warp::reply::Response, warp::Rejection> {
+    match check!(Database::get_clip_by_id(conn!(), clip_id).await) {
+        Some(c) if c.export_status == ExportStatus::Ready => {}
+        _ => return Ok(reply_status!(StatusCode::NOT_FOUND)),
+    }
+
+    let path = Clip::export_path(&CONFIG.get().unwrap().exports_dir, clip_id);
+    let file = check!(tokio::fs::File::open(&path).await);
+    let len = check!(file.metadata().await).len();
+
+    let mut res = warp::reply::Response::new(Body::wrap_stream(ReaderStream::new(file)));
+    let headers = res.headers_mut();
+    headers.insert("content-type", HeaderValue::from_static("video/mp4"));
+    headers.insert("content-length", HeaderValue::from(len));
+    headers.insert(
+        "content-disposition",
+        check!(HeaderValue::from_str(&format!(
+            "attachment; filename=\"clip-{}.mp4\"",
+            clip_id
+        ))),
     );
-    check!(Database::add_twitch_progress(&mut conn, user_id).await);
+    Ok(res)
+}
+
+async fn add_twitch_progress(user: User) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut conn = get_conn!();
+    log_api_call!(&mut conn, user);
+
+    check!(Database::add_twitch_progress(&mut conn, user.id).await);
     Ok(warp::reply::with_status(warp::reply(), StatusCode::CREATED))
 }
 
@@ -446,27 +800,109 @@ async fn add_web_error(request: WebErrorRequest) -> Result<impl warp::Reply, war
     Ok(warp::reply::reply())
 }
 
-async fn check_login(username: String, password: PasswordQuery) -> warp::reply::Response {
-    let f = async || {
-        check_username_password!(conn!(), &username, &password.password, Err(warp::reject()));
-        Ok(reply_status!(StatusCode::OK))
+#[derive(Clone, Debug, Serialize)]
+struct SessionResponse {
+    token: String,
+    expires_at: DateTime<Utc>,
+    username: String,
+}
+
+/// Start a new session for the user, returning the token in the body and setting it as a cookie.
+async fn start_session(
+    conn: &mut SqliteConnection,
+    user: User,
+    status: StatusCode,
+) -> anyhow::Result<warp::reply::Response> {
+    let lifetime = Duration::days(CONFIG.get().unwrap().session_lifetime_days);
+    let (token, expires_at) = Database::create_session(conn, user.id, lifetime).await?;
+
+    let cookie = format!(
+        "{}={}; HttpOnly; Path=/; Max-Age={}; SameSite=Lax",
+        SESSION_COOKIE,
+        token,
+        lifetime.num_seconds()
+    );
+    let body = SessionResponse {
+        token,
+        expires_at,
+        username: user.username,
     };
 
-    match f().await {
-        Ok(r) => r,
-        Err(_) => reply_status!(StatusCode::UNAUTHORIZED),
+    let reply = warp::reply::with_header(warp::reply::json(&body), "set-cookie", cookie);
+    Ok(reply_status!(reply, status))
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct LoginRequest {
+    username: String,
+    password: String,
+}
+async fn login(request: LoginRequest) -> Result<warp::reply::Response, warp::Rejection> {
+    let mut conn = get_conn!();
+
+    let user_id = match check!(Database::get_userid_by_username(&mut conn, &request.username).await)
+    {
+        None => return Ok(reply_status!(StatusCode::UNAUTHORIZED)),
+        Some(id) => id,
+    };
+    if !check!(Database::check_password(&mut conn, user_id, &request.password).await) {
+        return Ok(reply_status!(StatusCode::UNAUTHORIZED));
     }
+
+    let user = check!(check!(Database::get_user(&mut conn, user_id).await)
+        .ok_or_else(|| anyhow!("user {} disappeared", user_id)));
+    log_api_call!(&mut conn, user);
+
+    Ok(check!(start_session(&mut conn, user, StatusCode::OK).await))
+}
+async fn logout(token: Option<String>) -> Result<warp::reply::Response, warp::Rejection> {
+    if let Some(token) = token {
+        check!(Database::remove_session(conn!(), &token).await);
+    }
+
+    let cookie = format!(
+        "{}=; HttpOnly; Path=/; Max-Age=0; SameSite=Lax",
+        SESSION_COOKIE
+    );
+    Ok(warp::reply::with_header(warp::reply(), "set-cookie", cookie).into_response())
+}
+async fn current_user(user: User) -> Result<warp::reply::Json, warp::Rejection> {
+    Ok(warp::reply::json(&user))
+}
+
+async fn set_user_role(
+    username: String,
+    user: User,
+    role: Role,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let mut conn = get_conn!();
+    log_api_call!(&mut conn, user);
+
+    let user_id = match check!(Database::get_userid_by_username(&mut conn, &username).await) {
+        None => return Ok(reply_status!(StatusCode::NOT_FOUND)),
+        Some(id) => id,
+    };
+    check!(Database::set_user_role(&mut conn, user_id, role).await);
+
+    Ok(warp::reply().into_response())
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct SignupRequest {
+    password: String,
 }
 async fn signup(
     username: String,
-    password: PasswordQuery,
+    request: SignupRequest,
 ) -> Result<warp::reply::Response, warp::Rejection> {
     let mut conn = get_conn!();
 
-    check!(Database::signup(&mut conn, &username, &password.password).await);
-    let _ = Database::insert_api_call(&mut conn, &username, function!()).await;
+    let user = check!(Database::signup(&mut conn, &username, &request.password).await);
+    log_api_call!(&mut conn, user);
 
-    Ok(reply_status!(StatusCode::CREATED))
+    Ok(check!(
+        start_session(&mut conn, user, StatusCode::CREATED).await
+    ))
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -492,120 +928,199 @@ async fn add_web_visit(request: WebVisitRequest) -> Result<impl warp::Reply, war
 }
 
 pub async fn run_server() {
+    let config = CONFIG.get().unwrap();
+
     let endpoints = {
         let cors = warp::cors()
             .allow_any_origin()
-            .allow_methods(vec!["GET", "POST", "PUT"]);
+            .allow_methods(vec!["GET", "POST", "PUT", "PATCH", "DELETE"])
+            .allow_headers(vec!["authorization", "content-type"]);
         let log = warp::log("streamwatch");
 
         let api_paths = warp::path("api").and(
-            (warp::get().and(warp::path!("streams")).and_then(streams))
-                .or(warp::get()
-                    .and(warp::path!("processing"))
-                    .and_then(processing_streams))
-                .or(warp::patch()
-                    .and(warp::path!("streams"))
-                    .and_then(rescan_streams))
-                .or(warp::get()
-                    .and(warp::path!("persons"))
-                    .and_then(get_possible_persons))
-                .or(warp::get()
-                    .and(warp::path!("games"))
-                    .and_then(get_possible_games))
-                .or(warp::post()
-                    .and(warp::path!("games"))
-                    .and(warp::body::json())
-                    .and_then(add_possible_game))
-                .or(warp::put()
-                    .and(warp::path!("stream" / i64 / "games"))
-                    .and(warp::body::json())
-                    .and_then(replace_games))
-                .or(warp::put()
-                    .and(warp::path!("stream" / i64 / "persons"))
-                    .and(warp::body::json())
-                    .and_then(replace_persons))
-                .or(warp::get()
-                    .and(warp::path!("stream" / i64 / "chat"))
-                    .and(warp::query())
-                    .and_then(handle_chat_request))
-                .or(warp::get()
-                    .and(warp::path!("stream" / i64 / "hype"))
-                    .and_then(get_stream_hype))
-                .or(warp::get()
-                    .and(warp::path!("stream" / i64 / "clips"))
-                    .and(warp::query())
-                    .and_then(get_stream_clips))
-                .or(warp::post()
-                    .and(warp::path!("stream" / i64 / "rate"))
-                    .and(warp::query())
-                    .and(warp::body::json())
-                    .and_then(rate_stream))
-                .or(warp::put()
-                    .and(warp::path!("stream" / i64 / "title"))
-                    .and(warp::body::json())
-                    .and_then(set_custom_title))
-                .or(warp::get()
-                    .and(warp::path!("stream" / i64 / "otherProgress"))
-                    .and_then(get_stream_other_progress))
-                .or(warp::get()
-                    .and(warp::path!("user" / String))
-                    .and(warp::query())
-                    .then(check_login))
-                .or(warp::post()
-                    .and(warp::path!("user" / String))
-                    .and(warp::query())
-                    .and_then(signup))
-                .or(warp::put()
-                    .and(warp::path!("user" / String / "progress"))
-                    .and(warp::query())
-                    .and(warp::body::json())
-                    .and_then(set_streams_progress))
-                .or(warp::get()
-                    .and(warp::path!("user" / String / "progress"))
-                    .and(warp::query())
-                    .and_then(get_streams_progress))
-                .or(warp::get()
-                    .and(warp::path!("user" / String / "ratings"))
-                    .and(warp::query())
-                    .and_then(get_stream_ratings))
-                .or(warp::post()
-                    .and(warp::path!("user" / String / "twitchProgress"))
-                    .and(warp::query())
-                    .and_then(add_twitch_progress))
-                .or(warp::get()
-                    .and(warp::path!("parties"))
-                    .and_then(get_watch_parties))
-                .or(warp::get()
-                    .and(warp::path!("party" / "ws"))
-                    .and(warp::query())
-                    .and(warp::ws())
-                    .and_then(watch_party_ws))
-                .or(warp::get()
-                    .and(warp::path!("clips"))
-                    .and(warp::query())
-                    .and_then(get_all_clips))
-                .or(warp::post()
-                    .and(warp::path!("clips"))
-                    .and(warp::query())
-                    .and(warp::body::json())
-                    .and_then(create_clip))
-                .or(warp::put()
-                    .and(warp::path!("clips" / i64))
-                    .and(warp::query())
-                    .and(warp::body::json())
-                    .and_then(update_clip))
-                .or(warp::post()
-                    .and(warp::path!("clips" / i64 / "view"))
-                    .and(warp::query())
-                    .and_then(add_clip_view))
-                .or(warp::post()
-                    .and(warp::path!("web_error"))
-                    .and(warp::body::json())
-                    .and_then(add_web_error))
-                .or(warp::post()
-                    .and(warp::path!("visit"))
-                    .and(warp::body::json())
-                    .and_then(add_web_visit)),
+            (warp::get()
+                .and(warp::path!("streams"))
+                .and(warp::query())
+                .and_then(streams))
+            .or(warp::get()
+                .and(warp::path!("processing"))
+                .and_then(processing_streams))
+            .or(warp::get()
+                .and(warp::path!("jobs"))
+                .and(require_role(Role::Admin))
+                .and_then(get_jobs))
+            .or(warp::post()
+                .and(warp::path!("jobs" / i64 / "retry"))
+                .and(require_role(Role::Admin))
+                .and_then(retry_job))
+            .or(warp::post()
+                .and(warp::path!("jobs" / i64 / "cancel"))
+                .and(require_role(Role::Admin))
+                .and_then(cancel_job))
+            .or(warp::get()
+                .and(warp::path!("overrides"))
+                .and(require_role(Role::Admin))
+                .and_then(get_processing_overrides))
+            .or(warp::put()
+                .and(warp::path!("stream" / i64 / "overrides" / String))
+                .and(require_role(Role::Admin))
+                .and(warp::body::json())
+                .and_then(set_processing_override))
+            .or(warp::delete()
+                .and(warp::path!("stream" / i64 / "overrides" / String))
+                .and(require_role(Role::Admin))
+                .and_then(remove_processing_override))
+            .or(warp::patch()
+                .and(warp::path!("streams"))
+                .and(require_role(Role::Admin))
+                .and_then(rescan_streams))
+            .or(warp::get()
+                .and(warp::path!("persons"))
+                .and_then(get_possible_persons))
+            .or(warp::get()
+                .and(warp::path!("games"))
+                .and_then(get_possible_games))
+            .or(warp::post()
+                .and(warp::path!("games"))
+                .and(require_role(Role::Editor))
+                .and(warp::body::json())
+                .and_then(add_possible_game))
+            .or(warp::put()
+                .and(warp::path!("stream" / i64 / "games"))
+                .and(require_role(Role::Editor))
+                .and(warp::body::json())
+                .and_then(replace_games))
+            .or(warp::put()
+                .and(warp::path!("stream" / i64 / "persons"))
+                .and(require_role(Role::Editor))
+                .and(warp::body::json())
+                .and_then(replace_persons))
+            .or(warp::get()
+                .and(warp::path!("stream" / i64 / "chat"))
+                .and(warp::query())
+                .and_then(handle_chat_request))
+            .or(warp::post()
+                .and(warp::path!("stream" / i64 / "messages"))
+                .and(user())
+                .and(warp::body::json())
+                .and_then(post_message))
+            .or(warp::get()
+                .and(warp::path!("stream" / i64 / "chat" / "ws"))
+                .and(warp::ws())
+                .and_then(chat_replay_ws))
+            .or(warp::get()
+                .and(warp::path!("search" / "chat"))
+                .and(warp::query())
+                .and_then(search_chat))
+            .or(warp::get()
+                .and(warp::path!("stream" / i64 / "hype"))
+                .and(warp::query())
+                .and_then(get_stream_hype))
+            .or(warp::get()
+                .and(warp::path!("stream" / i64 / "clips"))
+                .and(optional_user())
+                .and_then(get_stream_clips))
+            .or(warp::get()
+                .and(warp::path!("stream" / i64 / "suggestions"))
+                .and_then(get_clip_suggestions))
+            .or(warp::post()
+                .and(warp::path!("suggestions" / i64 / "accept"))
+                .and(require_role(Role::Editor))
+                .and_then(accept_clip_suggestion))
+            .or(warp::post()
+                .and(warp::path!("suggestions" / i64 / "dismiss"))
+                .and(require_role(Role::Editor))
+                .and_then(dismiss_clip_suggestion))
+            .or(warp::post()
+                .and(warp::path!("stream" / i64 / "rate"))
+                .and(user())
+                .and(warp::body::json())
+                .and_then(rate_stream))
+            .or(warp::put()
+                .and(warp::path!("stream" / i64 / "title"))
+                .and(require_role(Role::Editor))
+                .and(warp::body::json())
+                .and_then(set_custom_title))
+            .or(warp::get()
+                .and(warp::path!("stream" / i64 / "otherProgress"))
+                .and_then(get_stream_other_progress))
+            .or(warp::post()
+                .and(warp::path!("login"))
+                .and(warp::body::json())
+                .and_then(login))
+            .or(warp::post()
+                .and(warp::path!("logout"))
+                .and(session_token())
+                .and_then(logout))
+            .or(warp::get()
+                .and(warp::path!("user"))
+                .and(user())
+                .and_then(current_user))
+            .or(warp::put()
+                .and(warp::path!("user" / "progress"))
+                .and(user())
+                .and(warp::body::json())
+                .and_then(set_streams_progress))
+            .or(warp::get()
+                .and(warp::path!("user" / "progress"))
+                .and(user())
+                .and_then(get_streams_progress))
+            .or(warp::get()
+                .and(warp::path!("user" / "ratings"))
+                .and(user())
+                .and_then(get_stream_ratings))
+            .or(warp::post()
+                .and(warp::path!("user" / "twitchProgress"))
+                .and(user())
+                .and_then(add_twitch_progress))
+            .or(warp::post()
+                .and(warp::path!("user" / String))
+                .and(warp::body::json())
+                .and_then(signup))
+            .or(warp::put()
+                .and(warp::path!("user" / String / "role"))
+                .and(require_role(Role::Admin))
+                .and(warp::body::json())
+                .and_then(set_user_role))
+            .or(warp::get()
+                .and(warp::path!("parties"))
+                .and_then(get_watch_parties))
+            .or(warp::get()
+                .and(warp::path!("party" / "ws"))
+                .and(warp::query())
+                .and(warp::ws())
+                .and_then(watch_party_ws))
+            .or(warp::get()
+                .and(warp::path!("clips"))
+                .and(optional_user())
+                .and_then(get_all_clips))
+            .or(warp::post()
+                .and(warp::path!("clips"))
+                .and(user())
+                .and(warp::body::json())
+                .and_then(create_clip))
+            .or(warp::put()
+                .and(warp::path!("clips" / i64))
+                .and(user())
+                .and(warp::body::json())
+                .and_then(update_clip))
+            .or(warp::post()
+                .and(warp::path!("clips" / i64 / "export"))
+                .and(user())
+                .and(warp::body::json())
+                .and_then(export_clip))
+            .or(warp::post()
+                .and(warp::path!("clips" / i64 / "view"))
+                .and(optional_user())
+                .and_then(add_clip_view))
+            .or(warp::post()
+                .and(warp::path!("web_error"))
+                .and(warp::body::json())
+                .and_then(add_web_error))
+            .or(warp::post()
+                .and(warp::path!("visit"))
+                .and(warp::body::json())
+                .and_then(add_web_visit)),
         );
         let static_paths = warp::path("video")
             .and(warp::fs::file("./dist/index.html"))
@@ -618,14 +1133,48 @@ pub async fn run_server() {
             .or(warp::path::end().and(warp::fs::dir("./dist")));
         let compressed = api_paths.or(static_paths).with(warp::compression::gzip());
 
-        let uncompressed = (warp::path("stream").and(warp::fs::dir(STREAMS_DIR)))
-            .or(warp::path("preview").and(warp::fs::dir("./previews")))
-            .or(warp::path("thumbnail").and(warp::fs::dir("./thumbnails")))
-            .or(warp::path("scrub_thumbnail").and(warp::fs::dir("./scrub_thumbnails")));
-
-        compressed.or(uncompressed).with(cors).with(log)
+        let library_paths = config
+            .libraries
+            .iter()
+            .map(|library| {
+                warp::path(library.name.clone())
+                    .and(warp::fs::dir(library.path.clone()))
+                    .boxed()
+            })
+            .reduce(|a, b| a.or(b).unify().boxed())
+            .unwrap();
+        // Streams used to be served from `/stream/{file}`, keep that working for the default
+        // library.
+        let stream_paths = library_paths
+            .or(warp::fs::dir(config.default_library().path.clone()))
+            .unify();
+
+        // Not compressed, video doesn't get any smaller and the response is streamed.
+        let play_paths = warp::get()
+            .and(warp::path!("api" / "stream" / i64 / "play.mp4"))
+            // every request starts an ffmpeg process
+            .and(user())
+            .and(warp::query())
+            .and_then(play_stream_mp4)
+            .or(warp::get()
+                .and(warp::path!("api" / "clips" / i64 / "download"))
+                .and_then(download_clip));
+
+        let uncompressed = (warp::path("stream").and(stream_paths))
+            .or(play_paths)
+            .or(warp::path("preview").and(warp::fs::dir(&config.previews_dir)))
+            .or(warp::path("thumbnail").and(warp::fs::dir(&config.thumbnails_dir)))
+            .or(warp::path("scrub_thumbnail").and(warp::fs::dir(&config.scrub_thumbnails_dir)))
+            .or(warp::path("hls").and(warp::fs::dir(&config.hls_dir)));
+
+        compressed
+            .or(uncompressed)
+            .recover(handle_rejection)
+            .with(cors)
+            .with(log)
     };
 
-    let addr = IpAddr::from_str("::0").unwrap();
-    warp::serve(endpoints).run((addr, 6070)).await;
+    warp::serve(endpoints)
+        .run((config.address, config.port))
+        .await;
 }
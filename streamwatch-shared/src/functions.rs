@@ -1,4 +1,7 @@
+use std::fmt;
+use std::io::{self, ErrorKind};
 use std::path::Path;
+use std::process::{ExitStatus, Output, Stdio};
 use std::time::Duration;
 
 use tokio::process::Command;
@@ -11,27 +14,203 @@ use regex::Regex;
 
 use anyhow::Result;
 
-pub async fn get_video_duration(path: &Path) -> Result<Duration> {
-    let output = Command::new("ffprobe")
-        .args(&[
-            "-v",
-            "error",
-            "-select_streams",
-            "v:0",
-            "-show_entries",
-            "format=duration",
-            "-of",
-            "csv=p=0",
-        ])
-        .arg(path.as_os_str())
+/// How many lines at the end of stderr are kept in a [`CommandError`].
+const STDERR_TAIL_LINES: usize = 20;
+
+/// Messages from ffmpeg and ffprobe that mean retrying won't help, because the input is broken or
+/// missing.
+const PERMANENT_FAILURES: &[&str] = &[
+    "No such file or directory",
+    "Invalid data found when processing input",
+    "moov atom not found",
+    "does not contain any stream",
+    "Output file #0 does not contain any stream",
+    "Invalid argument",
+    "Unknown encoder",
+    "Permission denied",
+];
+
+/// An external command (ffmpeg, ffprobe) that could not be run or exited unsuccessfully.
+#[derive(Debug)]
+pub enum CommandError {
+    Spawn {
+        program: String,
+        error: io::Error,
+    },
+    Failed {
+        program: String,
+        status: ExitStatus,
+        /// The last lines the command wrote to stderr.
+        stderr: String,
+    },
+}
+
+impl CommandError {
+    /// Whether running the command again might succeed. Missing programs, broken inputs and bad
+    /// arguments are permanent, everything else (being killed, running out of memory or disk
+    /// space, ...) is assumed to be transient.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            CommandError::Spawn { error, .. } => !matches!(
+                error.kind(),
+                ErrorKind::NotFound | ErrorKind::PermissionDenied
+            ),
+            CommandError::Failed { status, stderr, .. } => {
+                // killed by a signal
+                if status.code().is_none() {
+                    return true;
+                }
+                !PERMANENT_FAILURES.iter().any(|msg| stderr.contains(msg))
+            }
+        }
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Spawn { program, error } => {
+                write!(f, "failed to run {}: {}", program, error)
+            }
+            CommandError::Failed {
+                program,
+                status,
+                stderr,
+            } => {
+                write!(f, "{} exited with {}", program, status)?;
+                if !stderr.is_empty() {
+                    write!(f, ":\n{}", stderr)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for CommandError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CommandError::Spawn { error, .. } => Some(error),
+            CommandError::Failed { .. } => None,
+        }
+    }
+}
+
+/// The name of the program a command runs, looking through `nice`.
+fn program_name(cmd: &Command) -> String {
+    let cmd = cmd.as_std();
+    let program = cmd.get_program().to_string_lossy();
+    if program != "nice" {
+        return program.into_owned();
+    }
+
+    cmd.get_args()
+        .map(|arg| arg.to_string_lossy())
+        .find(|arg| !arg.starts_with('-'))
+        .unwrap_or(program)
+        .into_owned()
+}
+
+/// The last [`STDERR_TAIL_LINES`] lines of what a command wrote to stderr.
+pub fn stderr_tail(stderr: &[u8]) -> String {
+    let stderr = String::from_utf8_lossy(stderr);
+    let lines: Vec<&str> = stderr.trim_end().lines().collect();
+    let start = lines.len().saturating_sub(STDERR_TAIL_LINES);
+    lines[start..].join("\n")
+}
+
+/// Run the command to completion, capturing its output. Returns an error containing the exit
+/// status and stderr if it didn't exit successfully. The command is killed if the returned future
+/// is dropped before it finishes.
+pub async fn run_command(cmd: &mut Command) -> Result<Output, CommandError> {
+    let output = cmd
+        .kill_on_drop(true)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
         .output()
-        .await?;
+        .await
+        .map_err(|error| CommandError::Spawn {
+            program: program_name(cmd),
+            error,
+        })?;
+
+    if !output.status.success() {
+        return Err(CommandError::Failed {
+            program: program_name(cmd),
+            status: output.status,
+            stderr: stderr_tail(&output.stderr),
+        });
+    }
+
+    Ok(output)
+}
+
+pub async fn get_video_duration(path: &Path) -> Result<Duration> {
+    let output = run_command(
+        Command::new("ffprobe")
+            .args(&[
+                "-v",
+                "error",
+                "-select_streams",
+                "v:0",
+                "-show_entries",
+                "format=duration",
+                "-of",
+                "csv=p=0",
+            ])
+            .arg(path.as_os_str()),
+    )
+    .await?;
 
     let s = String::from_utf8(output.stdout)?;
     let seconds = s.trim().parse::<f64>()?;
     Ok(Duration::from_secs_f64(seconds))
 }
 
+/// The height in pixels of the first video stream.
+pub async fn get_video_height(path: &Path) -> Result<u32> {
+    let output = run_command(
+        Command::new("ffprobe")
+            .args([
+                "-v",
+                "error",
+                "-select_streams",
+                "v:0",
+                "-show_entries",
+                "stream=height",
+                "-of",
+                "csv=p=0",
+            ])
+            .arg(path.as_os_str()),
+    )
+    .await?;
+
+    let s = String::from_utf8(output.stdout)?;
+    Ok(s.trim().parse()?)
+}
+
+/// Whether the file has an audio stream.
+pub async fn has_audio(path: &Path) -> Result<bool> {
+    let output = run_command(
+        Command::new("ffprobe")
+            .args([
+                "-v",
+                "error",
+                "-select_streams",
+                "a:0",
+                "-show_entries",
+                "stream=index",
+                "-of",
+                "csv=p=0",
+            ])
+            .arg(path.as_os_str()),
+    )
+    .await?;
+
+    Ok(!output.stdout.trim_ascii().is_empty())
+}
+
 pub enum DateType {
     Full,
     DateOnly,
@@ -165,3 +165,22 @@ pub mod duration_milliseconds {
         }
     }
 }
+
+/// An optional value parsed from a string. Query strings only have strings, and a flattened struct
+/// in a query gets them without the numbers being parsed first.
+pub mod from_str_option {
+    use serde::{de, Deserialize, Deserializer};
+    use std::fmt;
+    use std::str::FromStr;
+
+    pub fn deserialize<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        Option::<String>::deserialize(deserializer)?
+            .map(|s| s.parse().map_err(de::Error::custom))
+            .transpose()
+    }
+}
@@ -1,5 +1,6 @@
 use crate::serde::{duration_milliseconds, duration_seconds, duration_seconds_float};
 
+use std::collections::HashMap;
 use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
 use std::time::Duration;
@@ -141,6 +142,7 @@ impl AsRef<str> for StreamFileName {
 #[derive(Clone, Debug, Serialize)]
 pub struct StreamInfo {
     pub id: i64,
+    pub library: String,
     pub title: Option<String>,
     pub title_type: String,
     pub file_name: StreamFileName,
@@ -153,22 +155,28 @@ pub struct StreamInfo {
     pub duration: Duration,
     pub has_preview: bool,
     pub thumbnail_count: usize,
+    /// Scrub thumbnails as separate files, from before there were sprite sheets. They are still
+    /// made for clients that don't use `scrub_thumbnails_vtt` yet.
     pub scrub_thumbnail_count: usize,
+    /// WebVTT thumbnails track pointing into the scrub thumbnail sprite sheets.
+    pub scrub_thumbnails_vtt: Option<String>,
     pub has_chat: bool,
     pub hype_average: Option<f64>,
+    /// Names of the HLS renditions that are available, empty if the stream hasn't been converted.
+    pub hls_renditions: Vec<String>,
 }
 
 impl StreamInfo {
-    pub fn preview_path(id: i64) -> PathBuf {
-        Path::new("./previews").join(id.to_string() + ".webm")
+    pub fn preview_path(previews_dir: &str, id: i64) -> PathBuf {
+        Path::new(previews_dir).join(id.to_string() + ".webm")
     }
     pub fn preview_url(&self) -> Option<String> {
         self.has_preview
             .then(|| format!("/preview/{}.webm", self.id))
     }
 
-    pub fn thumbnails_path(id: i64) -> PathBuf {
-        Path::new("./thumbnails").join(id.to_string())
+    pub fn thumbnails_path(thumbnails_dir: &str, id: i64) -> PathBuf {
+        Path::new(thumbnails_dir).join(id.to_string())
     }
     pub fn thumbnail_urls(&self) -> Vec<String> {
         (0..self.thumbnail_count)
@@ -176,14 +184,25 @@ impl StreamInfo {
             .collect()
     }
 
-    pub fn scrub_thumbnails_path(id: i64) -> PathBuf {
-        Path::new("./scrub_thumbnails").join(id.to_string())
+    pub fn scrub_thumbnails_path(scrub_thumbnails_dir: &str, id: i64) -> PathBuf {
+        Path::new(scrub_thumbnails_dir).join(id.to_string())
+    }
+    pub fn scrub_thumbnails_vtt_url(id: i64) -> String {
+        format!("/scrub_thumbnail/{}/thumbnails.vtt", id)
     }
     pub fn scrub_thumbnail_urls(&self) -> Vec<String> {
         (0..self.scrub_thumbnail_count)
             .map(|i| format!("/scrub_thumbnail/{}/{}.webp", self.id, i))
             .collect()
     }
+
+    pub fn hls_path(hls_dir: &str, id: i64) -> PathBuf {
+        Path::new(hls_dir).join(id.to_string())
+    }
+    /// A copy of the chat file that can be read from any point, see `chat::seek_index`.
+    pub fn chat_seek_index_path(chat_index_dir: &str, id: i64) -> PathBuf {
+        Path::new(chat_index_dir).join(id.to_string() + ".txt.zst")
+    }
 }
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct StreamDatapoint {
@@ -215,6 +234,48 @@ pub struct StreamJson {
     pub jumpcuts: Vec<StreamJumpcut>,
 }
 
+/// What a user is allowed to do. Every role includes the permissions of the roles before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Watch streams, keep progress, rate streams and make clips.
+    Viewer,
+    /// Edit stream metadata: titles, games and persons.
+    Editor,
+    /// Manage users and trigger maintenance tasks.
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Editor => "editor",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl std::str::FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "viewer" => Ok(Role::Viewer),
+            "editor" => Ok(Role::Editor),
+            "admin" => Ok(Role::Admin),
+            _ => Err(anyhow!("unknown role: {:?}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct User {
+    pub id: i64,
+    pub username: String,
+    pub role: Role,
+}
+
 #[derive(Clone, Debug, Serialize)]
 pub struct StreamProgress {
     #[serde(with = "duration_seconds_float")]
@@ -235,6 +296,43 @@ pub struct DbMessage {
     pub author_name: String,
 }
 
+/// Disables one kind of job for a stream, for instance because its recording or chat log makes
+/// ffmpeg or the chat reader fall over.
+#[derive(Clone, Debug, Serialize)]
+pub struct ProcessingOverride {
+    pub stream_id: i64,
+    pub kind: String,
+    pub reason: Option<String>,
+    #[serde(with = "ts_seconds")]
+    pub inserted_at: DateTime<Utc>,
+    pub inserted_by: Option<i64>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct JobInfo {
+    pub id: i64,
+    pub kind: String,
+    pub stream_id: Option<i64>,
+    pub status: String,
+    pub attempts: i64,
+    pub last_error: Option<String>,
+    #[serde(with = "ts_seconds")]
+    pub created_at: DateTime<Utc>,
+    #[serde(with = "ts_seconds_option")]
+    pub started_at: Option<DateTime<Utc>>,
+    #[serde(with = "ts_seconds_option")]
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct JobsOverview {
+    /// Number of queued jobs per job kind.
+    pub queued: HashMap<String, i64>,
+    pub running: Vec<JobInfo>,
+    /// The most recently failed jobs, newest first.
+    pub failed: Vec<JobInfo>,
+}
+
 #[derive(Clone, Debug, Serialize)]
 pub struct ConversionProgress {
     pub id: i64,
@@ -264,9 +362,28 @@ pub struct HypeDatapoint {
     pub hype: f64,
 }
 
+/// Downsampled hype, with every field as an array of the same length.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct HypeColumns {
+    /// Seconds since the epoch. The start of the bucket when averaging, the time of the selected
+    /// datapoint with LTTB.
+    pub ts: Vec<i64>,
+    pub loudness: Vec<Option<f64>>,
+    pub chat_hype: Vec<Option<f64>>,
+    pub hype: Vec<f64>,
+}
+
+/// A chat message posted by a viewer at a point in a VOD.
+#[derive(Clone, Debug, Deserialize)]
+pub struct PostMessageRequest {
+    /// How far into the stream, in milliseconds.
+    #[serde(with = "duration_milliseconds")]
+    pub offset: Duration,
+    pub message: String,
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct CreateClipRequest {
-    pub author_username: String,
     pub stream_id: i64,
     #[serde(with = "duration_milliseconds")]
     pub start_time: Duration,
@@ -287,10 +404,17 @@ pub struct Clip {
     pub title: Option<String>,
     pub created_at: i64,
     pub view_count: i64,
+    pub export_status: ExportStatus,
+    /// Whether the title is burned into the export.
+    pub export_has_title: bool,
+    /// Whether the chat is rendered next to the video in the export.
+    pub export_has_chat: bool,
 }
 impl Clip {
-    pub fn preview_path(id: i64) -> PathBuf {
-        Path::new("./previews/clips").join(id.to_string() + ".webm")
+    pub fn preview_path(previews_dir: &str, id: i64) -> PathBuf {
+        Path::new(previews_dir)
+            .join("clips")
+            .join(id.to_string() + ".webm")
     }
     pub fn preview_url(&self) -> String {
         format!("/preview/clips/{}.webm", self.id)
@@ -300,8 +424,10 @@ impl Clip {
         */
     }
 
-    pub fn thumbnail_path(id: i64) -> PathBuf {
-        Path::new("./thumbnails/clips").join(id.to_string() + ".webp")
+    pub fn thumbnail_path(thumbnails_dir: &str, id: i64) -> PathBuf {
+        Path::new(thumbnails_dir)
+            .join("clips")
+            .join(id.to_string() + ".webp")
     }
     pub fn thumbnail_url(&self) -> String {
         format!("/thumbnail/clips/{}.webp", self.id)
@@ -311,4 +437,89 @@ impl Clip {
             .collect()
         */
     }
+
+    pub fn export_path(exports_dir: &str, id: i64) -> PathBuf {
+        Path::new(exports_dir)
+            .join("clips")
+            .join(id.to_string() + ".mp4")
+    }
+}
+
+/// Whether a full quality export of a clip can be downloaded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportStatus {
+    /// No export was requested, or the clip changed after it was exported.
+    None,
+    /// An export job is queued or running.
+    Pending,
+    Ready,
+    /// The last export job failed.
+    Failed,
+}
+
+/// What happened to a suggested clip.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SuggestionStatus {
+    /// Not looked at yet.
+    Open,
+    /// Turned into a clip.
+    Accepted,
+    Dismissed,
+}
+
+impl SuggestionStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SuggestionStatus::Open => "open",
+            SuggestionStatus::Accepted => "accepted",
+            SuggestionStatus::Dismissed => "dismissed",
+        }
+    }
+}
+
+impl std::str::FromStr for SuggestionStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "open" => Ok(SuggestionStatus::Open),
+            "accepted" => Ok(SuggestionStatus::Accepted),
+            "dismissed" => Ok(SuggestionStatus::Dismissed),
+            _ => Err(anyhow!("unknown suggestion status: {:?}", s)),
+        }
+    }
+}
+
+/// A clip proposed by the highlight detector.
+#[derive(Clone, Debug, Serialize)]
+pub struct ClipSuggestion {
+    pub id: i64,
+    pub stream_id: i64,
+    #[serde(with = "duration_milliseconds")]
+    pub start_time: Duration,
+    #[serde(with = "duration_milliseconds")]
+    pub duration: Duration,
+    /// How sure the detector is that this is a highlight, from 0 to 1.
+    pub confidence: f64,
+    /// Taken from what chat was saying, if there was chat.
+    pub title: Option<String>,
+    pub status: SuggestionStatus,
+    /// The clip it was turned into, if it was accepted.
+    pub clip_id: Option<i64>,
+    pub created_at: i64,
+}
+
+/// A chat message that matched a search.
+#[derive(Clone, Debug, Serialize)]
+pub struct ChatSearchHit {
+    pub stream_id: i64,
+    #[serde(with = "ts_seconds")]
+    pub ts: DateTime<Utc>,
+    /// How far into the stream the message was sent.
+    #[serde(with = "duration_milliseconds")]
+    pub offset: Duration,
+    pub author: String,
+    pub text: String,
 }